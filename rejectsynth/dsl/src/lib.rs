@@ -11,7 +11,7 @@ pub enum ABC {
     G = 10,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Accidental {
     Natural,
     Sharp,
@@ -28,6 +28,131 @@ pub struct Key {
 pub enum Scale {
     Major,
     Minor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    HarmonicMinor,
+    MelodicMinor,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    Custom(CustomScale),
+}
+
+/// A scale made up of whatever steps the song asks for, e.g. `scale custom 2 2
+/// 3 2 3`. The steps don't have to add up to an octave, the scale just repeats
+/// every however many semitones they do add up to.
+#[derive(Debug, Clone, Copy)]
+pub struct CustomScale {
+    steps: [i8; 12],
+    len: u8,
+}
+
+impl CustomScale {
+    /// Takes either the steps between each note, or the notes themselves as
+    /// semitones above the tonic when the first one is 0 (`0 2 4 7 9`).
+    pub fn new(nums: &[u8]) -> Option<Self> {
+        let steps = match nums {
+            [0, offsets @ ..] => {
+                let mut prev = 0;
+                let mut steps = vec![];
+                for &offset in offsets.iter().chain([12].iter()) {
+                    if offset <= prev {
+                        return None;
+                    }
+                    steps.push(offset - prev);
+                    prev = offset;
+                }
+                steps
+            }
+            steps => steps.to_vec(),
+        };
+        if steps.is_empty() || steps.len() > 12 || steps.iter().any(|&step| step == 0 || step > 12)
+        {
+            return None;
+        }
+        let mut scale = Self {
+            steps: [0; 12],
+            len: steps.len() as u8,
+        };
+        for (dst, src) in scale.steps.iter_mut().zip(steps) {
+            *dst = src as i8;
+        }
+        Some(scale)
+    }
+
+    pub fn steps(&self) -> &[i8] {
+        &self.steps[..self.len as usize]
+    }
+}
+
+impl Scale {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "major" | "ionian" => Scale::Major,
+            "minor" | "aeolian" => Scale::Minor,
+            "dorian" => Scale::Dorian,
+            "phrygian" => Scale::Phrygian,
+            "lydian" => Scale::Lydian,
+            "mixolydian" => Scale::Mixolydian,
+            "locrian" => Scale::Locrian,
+            "harmonic_minor" => Scale::HarmonicMinor,
+            "melodic_minor" => Scale::MelodicMinor,
+            "major_pentatonic" => Scale::MajorPentatonic,
+            "minor_pentatonic" => Scale::MinorPentatonic,
+            "blues" => Scale::Blues,
+            _ => return None,
+        })
+    }
+}
+
+impl std::fmt::Display for Scale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Scale::Major => "major",
+            Scale::Minor => "minor",
+            Scale::Dorian => "dorian",
+            Scale::Phrygian => "phrygian",
+            Scale::Lydian => "lydian",
+            Scale::Mixolydian => "mixolydian",
+            Scale::Locrian => "locrian",
+            Scale::HarmonicMinor => "harmonic_minor",
+            Scale::MelodicMinor => "melodic_minor",
+            Scale::MajorPentatonic => "major_pentatonic",
+            Scale::MinorPentatonic => "minor_pentatonic",
+            Scale::Blues => "blues",
+            Scale::Custom(custom) => {
+                write!(f, "custom")?;
+                for step in custom.steps() {
+                    write!(f, " {step}")?;
+                }
+                return Ok(());
+            }
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let letter = match self.abc {
+            ABC::A => "A",
+            ABC::B => "B",
+            ABC::C => "C",
+            ABC::D => "D",
+            ABC::E => "E",
+            ABC::F => "F",
+            ABC::G => "G",
+        };
+        let accidental = match self.accidental {
+            Accidental::Natural => "",
+            Accidental::Sharp => "#",
+            Accidental::Flat => "b",
+        };
+        write!(f, "{letter}{accidental}")
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -43,16 +168,65 @@ impl Duration {
             denominator,
         }
     }
+
+    /// Multiplies the duration by `numerator / denominator`, as long as the
+    /// result still fits.
+    pub fn scaled(self, numerator: u8, denominator: u8) -> Option<Self> {
+        let mut numerator = self.numerator as u32 * numerator as u32;
+        let mut denominator = self.denominator as u32 * denominator as u32;
+        let (mut a, mut b) = (numerator, denominator);
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        if a > 1 {
+            numerator /= a;
+            denominator /= a;
+        }
+        Some(Self::new(
+            numerator.try_into().ok()?,
+            denominator.try_into().ok()?,
+        ))
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Plays `notes` notes in the time of `in_time_of`, e.g. `3:( ~1 ~2 ~3 )` is
+/// three eighth notes in the time of two, and `5:4( ... )` is a quintuplet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuplet {
+    pub notes: u8,
+    pub in_time_of: u8,
+}
+
+impl Tuplet {
+    /// Without an `in_time_of`, it's the biggest power of two below `notes`,
+    /// the way triplets go in the time of 2 and quintuplets in the time of 4,
+    /// except for duplets and quadruplets, which go in the time of 3.
+    pub fn new(notes: u8, in_time_of: Option<u8>) -> Option<Self> {
+        let in_time_of = match in_time_of {
+            Some(in_time_of) => in_time_of,
+            None if notes == 2 || notes == 4 => 3,
+            None if notes > 2 => ((notes as u16).next_power_of_two() / 2) as u8,
+            None => return None,
+        };
+        if notes == 0 || in_time_of == 0 {
+            return None;
+        }
+        Some(Self { notes, in_time_of })
+    }
+
+    pub fn scale(&self, duration: Duration) -> Option<Duration> {
+        duration.scaled(self.in_time_of, self.notes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct NotePitch {
     pub enum_: NotePitchEnum,
     pub octave: i8,
     pub accidental: Accidental,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NotePitchEnum {
     ScaleDegree(u8),
 }
@@ -63,8 +237,189 @@ pub enum Instruction {
     SetKey(Key),
     SetScale(Scale),
     PlayNote(Note),
+    PlayRest(Rest),
     SkipToNote,
     SetHarmony(Harmony),
+    BeginVoice(VoiceName),
+    EndVoice,
+    SetOctave(i8),
+    SetVolume(f32),
+    SetWaveform(WavePart, Waveform),
+    SetEnvelope(Envelope),
+    SetGain(Channel, f32),
+    SetTimeSignature(TimeSignature),
+    // a `|`, which doesn't change how anything sounds but has to come after a
+    // whole bar's worth of notes
+    BarLine,
+    // a `,`, which splits the notes around it into beats. it's up to the
+    // writer how they group them, unless they've asked for strict mode
+    BeatBoundary,
+    // `strict` makes every group of notes between commas last one beat, or as
+    // long as a bar of the time signature after it, like `strict 3/8`
+    SetStrict(Option<TimeSignature>),
+    // the notes between these have already been scaled by the tuplet, they're
+    // just here so the song can be laid out and highlighted
+    BeginTuplet(Tuplet),
+    EndTuplet,
+}
+
+/// How many beats there are in a bar and what kind of note gets a beat, e.g.
+/// `time 6/8`. Songs are in 4/4 until they say otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub beats: u8,
+    pub beat_unit: u8,
+}
+
+impl TimeSignature {
+    pub const COMMON: Self = Self {
+        beats: 4,
+        beat_unit: 4,
+    };
+
+    /// The beat unit has to be a whole note, half note, quarter note and so on.
+    pub fn new(beats: u8, beat_unit: u8) -> Option<Self> {
+        if beats == 0 || !beat_unit.is_power_of_two() || beat_unit > 64 {
+            return None;
+        }
+        Some(Self { beats, beat_unit })
+    }
+
+    /// How long a bar lasts in quarter notes, as a numerator and denominator.
+    pub fn bar_length(&self) -> (u32, u32) {
+        (self.beats as u32 * 4, self.beat_unit as u32)
+    }
+}
+
+/// What a `gain` directive turns up or down, by however many decibels.
+/// Melody and harmony are per voice, master is the whole mix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Melody,
+    Harmony,
+    Master,
+}
+
+impl Channel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "melody" => Channel::Melody,
+            "harmony" => Channel::Harmony,
+            "master" => Channel::Master,
+            _ => return None,
+        })
+    }
+}
+
+/// How a note's volume moves over time. The attack, decay and release are in
+/// milliseconds, and the sustain is the level the decay ends up at (between 0
+/// and 1). The release starts when the note ends, so it rings on over
+/// whatever comes next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    pub attack_ms: u16,
+    pub decay_ms: u16,
+    pub sustain: f32,
+    pub release_ms: u16,
+    pub curve: Curve,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self {
+            attack_ms: 10,
+            decay_ms: 0,
+            sustain: 1.,
+            release_ms: 10,
+            curve: Curve::Linear,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    Linear,
+    Exponential,
+}
+
+impl Curve {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "linear" => Curve::Linear,
+            "exp" => Curve::Exponential,
+            _ => return None,
+        })
+    }
+}
+
+/// The shape of the oscillators a voice plays with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    /// A square wave that's only high for this fraction of each cycle.
+    Pulse(f32),
+    Noise,
+}
+
+impl Waveform {
+    pub const DEFAULT_PULSE_WIDTH: f32 = 0.25;
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sine" => Waveform::Sine,
+            "saw" => Waveform::Saw,
+            "square" => Waveform::Square,
+            "triangle" => Waveform::Triangle,
+            "pulse" => Waveform::Pulse(Self::DEFAULT_PULSE_WIDTH),
+            "noise" => Waveform::Noise,
+            _ => return None,
+        })
+    }
+}
+
+/// Which part of a voice a `wave` directive changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavePart {
+    All,
+    Melody,
+    Harmony,
+}
+
+/// Voice names live inline so instructions can stay `Copy`, which is why
+/// they're capped at `VoiceName::MAX_LEN` bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VoiceName {
+    bytes: [u8; VoiceName::MAX_LEN],
+    len: u8,
+}
+
+impl VoiceName {
+    pub const MAX_LEN: usize = 16;
+
+    pub fn new(name: &str) -> Option<Self> {
+        if name.is_empty() || name.len() > Self::MAX_LEN {
+            return None;
+        }
+        let mut bytes = [0; Self::MAX_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Some(Self {
+            bytes,
+            len: name.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+}
+
+impl std::fmt::Debug for VoiceName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -77,6 +432,14 @@ pub struct Harmony {
 
 impl Harmony {
     pub fn parse(mut s: &str) -> Self {
+        // an explicit quality like IV(lydian) overrides the one from the case
+        let mut quality = None;
+        if let Some((base, name)) = s.strip_suffix(')').and_then(|s| s.split_once('(')) {
+            quality =
+                Some(Scale::from_name(name).unwrap_or_else(|| panic!("unknown scale: {name}")));
+            s = base;
+        }
+
         let add_7 = s.ends_with('7');
         if add_7 {
             s = &s[..s.len() - 1];
@@ -98,7 +461,7 @@ impl Harmony {
             _ => panic!("unknown harmony: {}", s),
         };
         Self {
-            scale,
+            scale: quality.unwrap_or(scale),
             degree,
             add_7,
             shift: 0,
@@ -113,3 +476,10 @@ pub struct Note {
     pub ties_to_next: bool,
     pub ties_to_prev: bool,
 }
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rest {
+    pub duration: Duration,
+    // `R` lets go of the harmony too, `r` keeps the chord ringing underneath
+    pub stops_harmony: bool,
+}
@@ -1,7 +1,7 @@
 #![feature(proc_macro_span)]
 
 use proc_macro::token_stream::IntoIter;
-use proc_macro::{TokenStream, TokenTree};
+use proc_macro::{Delimiter, TokenStream, TokenTree};
 use quote::quote;
 use std::iter::Peekable;
 use syn::__private::TokenStream2;
@@ -14,8 +14,10 @@ fn titlecase(mut s: String) -> String {
     s
 }
 
-fn parse(ts: TokenStream) -> TokenStream {
-    let mut code = quote! { let mut v = vec![]; };
+// pushes each instruction onto `v`, voice blocks get their contents pushed
+// in between BeginVoice and EndVoice
+fn instructions(ts: TokenStream, in_voice: bool) -> TokenStream2 {
+    let mut code = quote! {};
     let mut ts = ts.into_iter().peekable();
     while let Some(t) = ts.peek().cloned() {
         match &t {
@@ -35,34 +37,276 @@ fn parse(ts: TokenStream) -> TokenStream {
                     }
                     "key" => {
                         ts.next();
-                        let key = match ts.next() {
+                        let mut key = match ts.next() {
                             Some(TokenTree::Ident(ident)) => ident.to_string(),
                             _ => panic!("expected ident"),
                         };
+                        // flats come in as part of the ident (Bb). sharps are
+                        // a separate # punct, and have to be written `F #`
+                        // because rust reserves `F#` as a prefix
+                        let mut accidental = quote! { dsl::Accidental::Natural };
+                        if key.len() == 2 && key.ends_with('b') {
+                            key.truncate(1);
+                            accidental = quote! { dsl::Accidental::Flat };
+                        } else if let Some(TokenTree::Punct(punct)) = ts.peek() {
+                            if punct.to_string() == "#" {
+                                ts.next();
+                                accidental = quote! { dsl::Accidental::Sharp };
+                            }
+                        }
                         let key = titlecase(key);
                         let key = syn::Ident::new(&key, proc_macro2::Span::call_site());
                         code.extend(quote! {
                             v.push(dsl::Instruction::SetKey(dsl::Key {
                                 abc: dsl::ABC::#key,
-                                accidental: dsl::Accidental::Natural,
+                                accidental: #accidental,
                             }));
                         });
                     }
                     "scale" => {
                         ts.next();
-                        let scale = match ts.next() {
-                            Some(TokenTree::Ident(ident)) => titlecase(ident.to_string()),
+                        let (scale, line) = match ts.next() {
+                            Some(TokenTree::Ident(ident)) => {
+                                (ident.to_string(), ident.span().start().line())
+                            }
                             _ => panic!("expected ident"),
                         };
-                        let scale = syn::Ident::new(&scale, proc_macro2::Span::call_site());
+                        if scale == "custom" {
+                            // same as the grammar, only the numbers on the
+                            // directive's line are part of the scale
+                            let mut nums = vec![];
+                            while let Some(TokenTree::Literal(lit)) = ts.peek() {
+                                if lit.span().start().line() != line {
+                                    break;
+                                }
+                                nums.push(lit.to_string().parse::<u8>().expect("expected number"));
+                                ts.next();
+                            }
+                            if dsl::CustomScale::new(&nums).is_none() {
+                                panic!("bad custom scale: {nums:?}");
+                            }
+                            code.extend(quote! {
+                                v.push(dsl::Instruction::SetScale(dsl::Scale::Custom(
+                                    dsl::CustomScale::new(&[#(#nums),*]).unwrap()
+                                )));
+                            });
+                            continue;
+                        }
+                        if dsl::Scale::from_name(&scale).is_none() {
+                            panic!("unknown scale: {scale:?}");
+                        }
                         code.extend(quote! {
-                            v.push(dsl::Instruction::SetScale(dsl::Scale::#scale));
+                            v.push(dsl::Instruction::SetScale(dsl::Scale::from_name(#scale).unwrap()));
+                        });
+                    }
+                    "voice" => {
+                        ts.next();
+                        if in_voice {
+                            panic!("voices can't be nested");
+                        }
+                        let name = match ts.next() {
+                            Some(TokenTree::Ident(ident)) => ident.to_string(),
+                            _ => panic!("expected voice name"),
+                        };
+                        if dsl::VoiceName::new(&name).is_none() {
+                            panic!("voice name is too long: {name:?}");
+                        }
+                        let body = match ts.next() {
+                            Some(TokenTree::Group(group))
+                                if group.delimiter() == Delimiter::Brace =>
+                            {
+                                instructions(group.stream(), true)
+                            }
+                            _ => panic!("expected {{ after voice name"),
+                        };
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::BeginVoice(dsl::VoiceName::new(#name).unwrap()));
+                            #body
+                            v.push(dsl::Instruction::EndVoice);
+                        });
+                    }
+                    "time" => {
+                        ts.next();
+                        let time = time_signature(&mut ts);
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::SetTimeSignature(#time));
+                        });
+                    }
+                    "strict" => {
+                        ts.next();
+                        // `strict 1 2` is strict mode and then some notes
+                        let mut ahead = ts.clone();
+                        let has_group = matches!(
+                            (ahead.next(), ahead.next()),
+                            (Some(TokenTree::Literal(_)), Some(TokenTree::Punct(punct)))
+                                if punct.as_char() == '/'
+                        );
+                        let group = if has_group {
+                            let time = time_signature(&mut ts);
+                            quote! { Some(#time) }
+                        } else {
+                            quote! { None }
+                        };
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::SetStrict(#group));
+                        });
+                    }
+                    "octave" => {
+                        ts.next();
+                        let mut octave = String::new();
+                        if let Some(TokenTree::Punct(punct)) = ts.peek() {
+                            if punct.as_char() == '-' || punct.as_char() == '+' {
+                                octave.push(punct.as_char());
+                                ts.next();
+                            }
+                        }
+                        match ts.next() {
+                            Some(TokenTree::Literal(lit)) => octave.push_str(&lit.to_string()),
+                            _ => panic!("expected literal"),
+                        }
+                        let octave = octave.parse::<i8>().expect("expected octave");
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::SetOctave(#octave));
+                        });
+                    }
+                    "volume" => {
+                        ts.next();
+                        let volume = match ts.next() {
+                            Some(TokenTree::Literal(lit)) => lit.to_string(),
+                            _ => panic!("expected literal"),
+                        };
+                        let volume = volume.parse::<f32>().expect("expected volume");
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::SetVolume(#volume));
+                        });
+                    }
+                    "env" => {
+                        let line = match ts.next() {
+                            Some(TokenTree::Ident(ident)) => ident.span().start().line(),
+                            _ => unreachable!(),
+                        };
+                        let mut nums = vec![];
+                        for _ in 0..4 {
+                            match ts.next() {
+                                Some(TokenTree::Literal(lit)) => nums.push(lit.to_string()),
+                                _ => panic!("env needs an attack, decay, sustain and release"),
+                            }
+                        }
+                        let ms = |ms: &str| ms.parse::<u16>().expect("expected milliseconds");
+                        let (attack_ms, decay_ms, release_ms) =
+                            (ms(&nums[0]), ms(&nums[1]), ms(&nums[3]));
+                        let sustain = nums[2].parse::<f32>().expect("expected sustain");
+                        if !(0. ..=1.).contains(&sustain) {
+                            panic!("sustain has to be between 0 and 1: {sustain}");
+                        }
+                        let mut curve = quote! { dsl::Curve::Linear };
+                        if let Some(TokenTree::Ident(ident)) = ts.peek() {
+                            let name = ident.to_string();
+                            if ident.span().start().line() == line {
+                                if dsl::Curve::from_name(&name).is_none() {
+                                    panic!("unknown curve: {name:?}");
+                                }
+                                curve = quote! { dsl::Curve::from_name(#name).unwrap() };
+                                ts.next();
+                            }
+                        }
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::SetEnvelope(dsl::Envelope {
+                                attack_ms: #attack_ms,
+                                decay_ms: #decay_ms,
+                                sustain: #sustain,
+                                release_ms: #release_ms,
+                                curve: #curve,
+                            }));
+                        });
+                    }
+                    "gain" => {
+                        ts.next();
+                        let channel = match ts.next() {
+                            Some(TokenTree::Ident(ident)) => ident.to_string(),
+                            _ => panic!("expected ident"),
+                        };
+                        if dsl::Channel::from_name(&channel).is_none() {
+                            panic!("unknown channel: {channel:?}");
+                        }
+                        let mut db = String::new();
+                        if let Some(TokenTree::Punct(punct)) = ts.peek() {
+                            if punct.as_char() == '-' || punct.as_char() == '+' {
+                                db.push(punct.as_char());
+                                ts.next();
+                            }
+                        }
+                        match ts.next() {
+                            Some(TokenTree::Literal(lit)) => db.push_str(&lit.to_string()),
+                            _ => panic!("expected literal"),
+                        }
+                        let db = db.parse::<f32>().expect("expected decibels");
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::SetGain(
+                                dsl::Channel::from_name(#channel).unwrap(),
+                                #db,
+                            ));
+                        });
+                    }
+                    "wave" | "instrument" => {
+                        ts.next();
+                        let (mut waveform, line) = match ts.next() {
+                            Some(TokenTree::Ident(ident)) => {
+                                (ident.to_string(), ident.span().start().line())
+                            }
+                            _ => panic!("expected ident"),
+                        };
+                        let part = match waveform.as_str() {
+                            "melody" | "harmony" => {
+                                let part = syn::Ident::new(
+                                    &titlecase(waveform),
+                                    proc_macro2::Span::call_site(),
+                                );
+                                waveform = match ts.next() {
+                                    Some(TokenTree::Ident(ident)) => ident.to_string(),
+                                    _ => panic!("expected ident"),
+                                };
+                                quote! { dsl::WavePart::#part }
+                            }
+                            _ => quote! { dsl::WavePart::All },
+                        };
+                        if dsl::Waveform::from_name(&waveform).is_none() {
+                            panic!("unknown waveform: {waveform:?}");
+                        }
+                        let waveform_name = waveform;
+                        let mut waveform =
+                            quote! { dsl::Waveform::from_name(#waveform_name).unwrap() };
+                        // like the grammar, a pulse's width has to be on the same line
+                        if let Some(TokenTree::Literal(lit)) = ts.peek() {
+                            if waveform_name == "pulse" && lit.span().start().line() == line {
+                                let width = lit.to_string().parse::<f32>().expect("expected width");
+                                if !(width > 0. && width < 1.) {
+                                    panic!("pulse width has to be between 0 and 1: {width}");
+                                }
+                                waveform = quote! { dsl::Waveform::Pulse(#width) };
+                                ts.next();
+                            }
+                        }
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::SetWaveform(#part, #waveform));
                         });
                     }
                     // underscore means it's a note with preceding tie
                     "_" => code.extend(note_literal(&mut ts)),
+                    "r" | "R" => code.extend(note_literal(&mut ts)),
                     harmony if harmony.starts_with(['i', 'v', 'I', 'V']) => {
                         ts.next();
+                        let mut harmony = harmony.to_string();
+                        // a chord quality like IV(lydian) comes in as a group
+                        if let Some(TokenTree::Group(group)) = ts.peek() {
+                            assert_eq!(group.delimiter(), Delimiter::Parenthesis);
+                            let quality = group.stream().to_string();
+                            if dsl::Scale::from_name(&quality).is_none() {
+                                panic!("unknown scale: {quality:?}");
+                            }
+                            harmony = format!("{harmony}({quality})");
+                            ts.next();
+                        }
                         let colon = ts.next().unwrap();
                         code.extend(quote! {
                             v.push(dsl::Instruction::SetHarmony(dsl::Harmony::parse(#harmony)));
@@ -79,7 +323,9 @@ fn parse(ts: TokenStream) -> TokenStream {
                         code.extend(note_literal(&mut ts));
                     }
                     "," => {
-                        // println!("skipping punctuation for now: {t:?}");
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::BeatBoundary);
+                        });
                         ts.next();
                     }
                     ">" => {
@@ -88,22 +334,91 @@ fn parse(ts: TokenStream) -> TokenStream {
                         });
                         ts.next();
                     }
+                    "|" => {
+                        code.extend(quote! {
+                            v.push(dsl::Instruction::BarLine);
+                        });
+                        ts.next();
+                    }
                     _ => panic!("unknown punct: {punct:?}"),
                 }
             }
+            TokenTree::Literal(_) if is_tuplet(&ts) => {
+                code.extend(tuplet(&mut ts, in_voice));
+            }
             TokenTree::Literal(_) => {
                 code.extend(note_literal(&mut ts));
             }
             TokenTree::Group(_) => unreachable!(),
         }
     }
-    let code = quote! {
-        {
-            #code
-            v
+    code
+}
+
+// `3:( ... )` or `5:4( ... )`, which starts with a number like a note does
+fn is_tuplet(ts: &Peekable<IntoIter>) -> bool {
+    let mut ahead = ts.clone();
+    ahead.next();
+    matches!(ahead.next(), Some(TokenTree::Punct(punct)) if punct.as_char() == ':')
+}
+
+fn tuplet(ts: &mut Peekable<IntoIter>, in_voice: bool) -> TokenStream2 {
+    let number = |t: Option<TokenTree>| match t {
+        Some(TokenTree::Literal(lit)) => lit.to_string().parse::<u8>().ok(),
+        _ => None,
+    };
+    let notes = number(ts.next()).expect("expected number of notes in the tuplet");
+    ts.next();
+    let in_time_of = match ts.peek() {
+        Some(TokenTree::Literal(_)) => number(ts.next()),
+        _ => None,
+    };
+    let Some(dsl::Tuplet { notes, in_time_of }) = dsl::Tuplet::new(notes, in_time_of) else {
+        panic!("expected a tuplet like 3:( ... ) or 5:4( ... )");
+    };
+    let body = match ts.next() {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis => {
+            instructions(group.stream(), in_voice)
         }
+        _ => panic!("expected ( after tuplet"),
     };
-    code.into()
+    quote! {
+        {
+            let tuplet = dsl::Tuplet { notes: #notes, in_time_of: #in_time_of };
+            let start = v.len();
+            #body
+            for instruction in &mut v[start..] {
+                match instruction {
+                    dsl::Instruction::PlayNote(dsl::Note { duration, .. })
+                    | dsl::Instruction::PlayRest(dsl::Rest { duration, .. }) => {
+                        *duration = tuplet.scale(*duration).expect("notes too short for the tuplet");
+                    }
+                    _ => {}
+                }
+            }
+            v.insert(start, dsl::Instruction::BeginTuplet(tuplet));
+            v.push(dsl::Instruction::EndTuplet);
+        }
+    }
+}
+
+// like 3/4
+fn time_signature(ts: &mut Peekable<IntoIter>) -> TokenStream2 {
+    let mut nums = vec![];
+    for expected in ["literal", "/", "literal"] {
+        match ts.next() {
+            Some(TokenTree::Literal(lit)) if expected == "literal" => {
+                nums.push(lit.to_string().parse::<u8>().expect("expected number"))
+            }
+            Some(TokenTree::Punct(punct)) if expected == "/" && punct.as_char() == '/' => {}
+            _ => panic!("expected a time signature like 3/4"),
+        }
+    }
+    let (beats, beat_unit) = (nums[0], nums[1]);
+    if dsl::TimeSignature::new(beats, beat_unit).is_none() {
+        panic!("bad time signature: {beats}/{beat_unit}");
+    }
+    quote! { dsl::TimeSignature::new(#beats, #beat_unit).unwrap() }
 }
 
 fn note_literal(ts: &mut Peekable<IntoIter>) -> TokenStream2 {
@@ -129,8 +444,23 @@ fn note_literal(ts: &mut Peekable<IntoIter>) -> TokenStream2 {
         }
     }
 
-    let mut accidental = quote! { dsl::Accidental::Natural };
     let lit = ts.next().unwrap();
+    if let TokenTree::Ident(ident) = &lit {
+        let stops_harmony = match ident.to_string().as_str() {
+            "r" => false,
+            "R" => true,
+            _ => panic!("unknown ident: {ident:?}"),
+        };
+        duration_suffix(ts, &lit, &mut numerator, &mut denominator);
+        return quote! {
+            v.push(dsl::Instruction::PlayRest(dsl::Rest {
+                duration: dsl::Duration::new(#numerator, #denominator),
+                stops_harmony: #stops_harmony,
+            }));
+        };
+    }
+
+    let mut accidental = quote! { dsl::Accidental::Natural };
     let mut lit_text = lit.to_string();
     if lit_text.ends_with('b') {
         accidental = quote! { dsl::Accidental::Flat };
@@ -157,23 +487,7 @@ fn note_literal(ts: &mut Peekable<IntoIter>) -> TokenStream2 {
         }
     }
 
-    while let Some(TokenTree::Punct(punct)) = ts.peek() {
-        let no_ws = lit.span().end().column() == punct.span().start().column();
-        if punct.to_string() == "~" && no_ws {
-            if numerator == 1 {
-                numerator = 2
-            } else {
-                numerator += 2
-            };
-            ts.next();
-        } else if punct.to_string() == "." {
-            ts.next();
-            numerator *= 3;
-            denominator *= 2;
-        } else {
-            break;
-        }
-    }
+    duration_suffix(ts, &lit, &mut numerator, &mut denominator);
 
     quote! {
         v.push(dsl::Instruction::PlayNote (dsl::Note{
@@ -189,10 +503,49 @@ fn note_literal(ts: &mut Peekable<IntoIter>) -> TokenStream2 {
     }
 }
 
+// the ~s and .s that come right after a note (or rest)
+fn duration_suffix(
+    ts: &mut Peekable<IntoIter>,
+    lit: &TokenTree,
+    numerator: &mut u8,
+    denominator: &mut u8,
+) {
+    while let Some(TokenTree::Punct(punct)) = ts.peek() {
+        let no_ws = lit.span().end().column() == punct.span().start().column();
+        if punct.to_string() == "~" && no_ws {
+            if *numerator == 1 {
+                *numerator = 2
+            } else {
+                *numerator += 2
+            };
+            ts.next();
+        } else if punct.to_string() == "." {
+            ts.next();
+            *numerator *= 3;
+            *denominator *= 2;
+        } else {
+            break;
+        }
+    }
+}
+
+/// The song syntax as Rust tokens, turned into a `Vec<dsl::Instruction>` at
+/// compile time. It's the same as the text syntax except that a sharp key has
+/// to be written with a space, `key F #`, because Rust won't lex `F#` at all.
+/// Flat keys (`key Bb`) and sharp and flat notes (`4#`, `3b`) are written the
+/// same as in the text.
 #[proc_macro]
 pub fn m(ts: TokenStream) -> TokenStream {
     // for t in ts.clone() {
     //     println!("t: {:?}", t);
     // }
-    parse(ts)
+    let code = instructions(ts, false);
+    let code = quote! {
+        {
+            let mut v = vec![];
+            #code
+            v
+        }
+    };
+    code.into()
 }
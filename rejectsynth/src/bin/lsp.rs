@@ -0,0 +1,246 @@
+//! A Language Server Protocol server for songs, over stdin and stdout, so any
+//! editor gets the same diagnostics and highlighting as the VS Code extension,
+//! along with hovering over notes and formatting.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use rejectsynth as lib;
+
+// the same ones the VS Code extension uses
+const TOKEN_TYPES: [&str; 5] = ["keyword", "number", "operator", "parameter", "comment"];
+
+fn main() -> io::Result<()> {
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+    // the text of every open document, by URI
+    let mut docs: HashMap<String, String> = HashMap::new();
+    let mut is_shut_down = false;
+
+    while let Some(message) = read_message(&mut stdin)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let doc = docs.get(uri);
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    // the whole document gets sent every time it changes
+                    "textDocumentSync": 1,
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": [] },
+                        "full": true,
+                    },
+                    "hoverProvider": true,
+                    "documentFormattingProvider": true,
+                },
+                "serverInfo": { "name": "rejectsynth-lsp" },
+            }),
+            "shutdown" => {
+                is_shut_down = true;
+                Value::Null
+            }
+            "exit" => std::process::exit(if is_shut_down { 0 } else { 1 }),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                docs.insert(uri.to_string(), text.to_string());
+                publish_diagnostics(&mut stdout, uri, text)?;
+                continue;
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                let text = changes.and_then(|changes| changes.last()?["text"].as_str());
+                if let Some(text) = text {
+                    docs.insert(uri.to_string(), text.to_string());
+                    publish_diagnostics(&mut stdout, uri, text)?;
+                }
+                continue;
+            }
+            "textDocument/didClose" => {
+                docs.remove(uri);
+                publish_diagnostics(&mut stdout, uri, "")?;
+                continue;
+            }
+            "textDocument/semanticTokens/full" => {
+                doc.map_or(Value::Null, |doc| semantic_tokens(doc))
+            }
+            "textDocument/hover" => doc.map_or(Value::Null, |doc| hover(doc, &params["position"])),
+            "textDocument/formatting" => doc.map_or(Value::Null, |doc| formatting(doc)),
+            _ => {
+                // notifications we don't care about don't get an answer
+                if let Some(id) = message.get("id") {
+                    let error = json!({ "code": -32601, "message": format!("unsupported method: {method}") });
+                    write_message(
+                        &mut stdout,
+                        &json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+                    )?;
+                }
+                continue;
+            }
+        };
+        if let Some(id) = message.get("id") {
+            write_message(
+                &mut stdout,
+                &json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// None once the editor's gone away
+fn read_message(r: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if r.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok();
+            }
+        }
+    }
+    let Some(content_length) = content_length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no Content-Length",
+        ));
+    };
+    let mut content = vec![0; content_length];
+    r.read_exact(&mut content)?;
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+fn write_message(w: &mut impl Write, message: &Value) -> io::Result<()> {
+    let content = message.to_string();
+    write!(w, "Content-Length: {}\r\n\r\n{content}", content.len())?;
+    w.flush()
+}
+
+fn publish_diagnostics(w: &mut impl Write, uri: &str, text: &str) -> io::Result<()> {
+    let errors = match lib::parse(text) {
+        Ok(parse_result) => parse_result.diagnostics,
+        Err(e) => vec![e],
+    };
+    let diagnostics = errors
+        .into_iter()
+        .map(|e| {
+            json!({
+                "range": range(text, e.l, e.r),
+                "severity": if e.is_warning { 2 } else { 1 },
+                "source": "rejectsynth",
+                "message": e.to_string(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let params = json!({ "uri": uri, "diagnostics": diagnostics });
+    write_message(
+        w,
+        &json!({ "jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": params }),
+    )
+}
+
+fn semantic_tokens(text: &str) -> Value {
+    let line_starts = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<_>>();
+    // every token is where it is relative to the one before it
+    let mut data = vec![];
+    let (mut prev_line, mut prev_character) = (0, 0);
+    for syntax in lib::syntax(text).unwrap_or_default() {
+        let token_type = match syntax.node_type().as_str() {
+            "PlayNote" | "PlayRest" => "number",
+            "SkipToNote" | "EndVoice" | "BarLine" | "BeatBoundary" | "BeginTuplet"
+            | "EndTuplet" => "operator",
+            "SetHarmony" => "parameter",
+            "Comment" => "comment",
+            _ => "keyword",
+        };
+        let line_start = line_starts[syntax.line_no];
+        let l = line_start + syntax.col_no;
+        let character = utf16_len(&text[line_start..l]);
+        if syntax.line_no != prev_line {
+            prev_character = 0;
+        }
+        data.extend([
+            syntax.line_no - prev_line,
+            character - prev_character,
+            utf16_len(&text[l..l + syntax.len]),
+            TOKEN_TYPES.iter().position(|&t| t == token_type).unwrap(),
+            0,
+        ]);
+        (prev_line, prev_character) = (syntax.line_no, character);
+    }
+    json!({ "data": data })
+}
+
+fn hover(text: &str, position: &Value) -> Value {
+    let Some(note) = offset(text, position).and_then(|offset| lib::describe_at(text, offset))
+    else {
+        return Value::Null;
+    };
+    let mut lines = vec![match (note.name(), note.freq()) {
+        (Some(name), Some(freq)) => format!("{name}, {freq:.2} Hz"),
+        _ => "rest".to_string(),
+    }];
+    let beats = (note.beats * 1000.).round() / 1000.;
+    let plural = if beats == 1. { "" } else { "s" };
+    lines.push(format!("{beats} beat{plural}, {:.0} ms", note.duration_ms));
+    if !note.chord().is_empty() {
+        lines.push(format!("over {}", note.chord().join(" ")));
+    }
+    json!({
+        "contents": { "kind": "plaintext", "value": lines.join("\n") },
+        "range": range(text, note.l, note.r),
+    })
+}
+
+fn formatting(text: &str) -> Value {
+    match lib::format_song(text) {
+        Ok(formatted) => json!([{ "range": range(text, 0, text.len()), "newText": formatted }]),
+        Err(_) => Value::Null,
+    }
+}
+
+// editors count characters in UTF-16, the song counts bytes
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": utf16_len(&before[line_start..]),
+    })
+}
+
+fn range(text: &str, l: usize, r: usize) -> Value {
+    json!({ "start": position(text, l), "end": position(text, r.max(l)) })
+}
+
+fn offset(text: &str, position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    let line_start = match line {
+        0 => 0,
+        line => text.match_indices('\n').nth(line - 1)?.0 + 1,
+    };
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(line_start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(text.len())
+}
+
+fn utf16_len(s: &str) -> usize {
+    s.encode_utf16().count()
+}
@@ -1,8 +1,10 @@
 #![allow(special_module_name)]
-#![feature(iter_array_chunks)]
 #![feature(anonymous_lifetime_in_impl_trait)]
 #![feature(array_windows)]
 
+use std::io::Read;
+
+use dsl::Instruction;
 use psimple::Simple;
 use pulse::sample::{Format, Spec};
 use pulse::stream::Direction;
@@ -12,38 +14,332 @@ use rejectsynth as lib;
 const BUFFER_SIZE: usize = 1024;
 const BUFFER_SIZE_HALF: usize = BUFFER_SIZE / 2;
 
+const USAGE: &str = "usage: rejectplay <command> [options] <song.rej | song.mid | ->
+
+commands:
+  play              play the song through PulseAudio
+  render            render the song to a WAV file
+  export-midi       write the song out as a MIDI file
+  import-midi       turn a MIDI file's melody into a song
+  check             parse the song and report any errors or warnings
+  fmt               tidy up the song's layout
+
+options:
+  --bpm <bpm>       play at this tempo instead of the song's
+  --key <key>       play in this key instead of the song's, e.g. E, F# or Bb
+                    (import-midi: write the song in this key)
+  --scale <scale>   write the song in this scale, e.g. minor (import-midi)
+  --start <pos>     only play notes from here on, as line:col or a byte offset
+  --end <pos>       only play notes up to here, as line:col or a byte offset
+  --device <name>   PulseAudio sink to play through (play)
+  -o, --output <path>
+                    where to write the WAV or MIDI file (render, export-midi),
+                    or the song (import-midi and fmt, defaults to stdout)
+  --format s16|f32  WAV sample format, defaults to s16 (render)
+  --sample-rate <hz>
+                    samples a second, e.g. 48000, defaults to 44100 (play, render)";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Play,
+    Render,
+    ExportMidi,
+    ImportMidi,
+    Check,
+    Fmt,
+}
+
+struct Args {
+    command: Command,
+    song_path: String,
+    bpm: Option<u16>,
+    key: Option<dsl::Key>,
+    scale: Option<dsl::Scale>,
+    start: Option<String>,
+    end: Option<String>,
+    device: Option<String>,
+    out_path: Option<String>,
+    format: lib::WavFormat,
+    sample_rate: Option<u32>,
+}
+
 fn main() {
-    let song = r#"bpm 70
-key E
-scale minor
-
-III: ~4 ~3 ~2 ~3 , 4 ~-5 ~-7
-i: ~2 , ~1 , 1~.
-iv: ~1 ~5 ~4 ~3 , VII: 4. ~~5 ~~6#
-v: ~7 ~5 , 5~.
-VI: ~5 ~6, ~5  ~4 , vi7: 3. ~4
-III: ~5 ~-5 , ~-6, ~-7 , i: ~2 ~1 , 1_ iv: , _~1 ~1
-~2 ~3 , 4~. i: ~3
-~4 ~5 VII7: 6~.
-"#;
-
-    let song = lib::grammar::song(song).unwrap();
-    dbg!(&song);
-
-    let pulse = init_pulse();
+    let args = parse_args(std::env::args().skip(1));
+    if args.command == Command::ImportMidi {
+        import_midi(&args);
+        return;
+    }
+    let (origin, song_text) = read_song(&args.song_path);
+    let song = lib::parse(&song_text).unwrap_or_else(|e| {
+        eprint!("{}", e.render(&song_text, &origin));
+        std::process::exit(1);
+    });
+    for warning in &song.diagnostics {
+        eprint!("{}", warning.render(&song_text, &origin));
+    }
+
+    if args.command == Command::Check {
+        if !song.diagnostics.is_empty() {
+            std::process::exit(1);
+        }
+        println!(
+            "{origin}: ok, {} instructions",
+            song.spanned_instructions.len()
+        );
+        return;
+    }
+    if args.command == Command::Fmt {
+        // it's already parsed, so it's not going to fail now
+        let formatted = lib::format_song(&song_text).unwrap();
+        match &args.out_path {
+            Some(out_path) => std::fs::write(out_path, formatted)
+                .unwrap_or_else(|e| fail(&format!("couldn't write {out_path}: {e}"))),
+            None => print!("{formatted}"),
+        }
+        return;
+    }
+
+    let selection = match (&args.start, &args.end) {
+        (None, None) => None,
+        (start, end) => {
+            let l = start.as_ref().map_or(0, |pos| position(&song_text, pos));
+            let r = end
+                .as_ref()
+                .map_or(song_text.len(), |pos| position(&song_text, pos));
+            Some(l..=r)
+        }
+    };
+
+    let mut instructions = song.instructions();
+    for instruction in &mut instructions {
+        match instruction {
+            Instruction::SetBPM(bpm) => *bpm = args.bpm.unwrap_or(*bpm),
+            Instruction::SetKey(key) => *key = args.key.unwrap_or(*key),
+            _ => {}
+        }
+    }
+    if args.command == Command::ExportMidi {
+        // the overrides have to be instructions for them to make it into the
+        // tempo and key signature tracks
+        let overrides = [
+            args.bpm.map(Instruction::SetBPM),
+            args.key.map(Instruction::SetKey),
+        ];
+        instructions.splice(0..0, overrides.into_iter().flatten());
+        let out_path = args.out_path.as_deref().unwrap();
+        let out = std::fs::File::create(out_path)
+            .unwrap_or_else(|e| fail(&format!("couldn't create {out_path}: {e}")));
+        lib::write_midi(std::io::BufWriter::new(out), &instructions)
+            .unwrap_or_else(|e| fail(&format!("couldn't write {out_path}: {e}")));
+        return;
+    }
+
+    let mut ctx = lib::SongContext::default(instructions);
+    if let Some(bpm) = args.bpm {
+        ctx.set_bpm(bpm);
+    }
+    if let Some(key) = args.key {
+        ctx.set_key(key);
+    }
+    if let Some(sample_rate) = args.sample_rate {
+        ctx.set_sample_rate(sample_rate);
+    }
+
+    let next_samples = |ctx: &mut lib::SongContext| match &selection {
+        Some(selection) => ctx.iterate_selection(&song.spanned_instructions, selection),
+        None => ctx.iterate(),
+    };
+
+    match args.command {
+        Command::Play => play(&mut ctx, args.device.as_deref(), next_samples),
+        Command::Render => {
+            let mut samples = vec![];
+            while !ctx.is_done() {
+                samples.extend(next_samples(&mut ctx));
+            }
+            let out_path = args.out_path.as_deref().unwrap();
+            let out = std::fs::File::create(out_path)
+                .unwrap_or_else(|e| fail(&format!("couldn't create {out_path}: {e}")));
+            lib::write_wav(out, &samples, ctx.sample_rate(), args.format)
+                .unwrap_or_else(|e| fail(&format!("couldn't write {out_path}: {e}")));
+        }
+        Command::ExportMidi | Command::ImportMidi | Command::Check | Command::Fmt => {
+            unreachable!()
+        }
+    }
+}
+
+fn parse_args(mut argv: impl Iterator<Item = String>) -> Args {
+    let command = match argv.next().as_deref() {
+        Some("play") => Command::Play,
+        Some("render") => Command::Render,
+        Some("export-midi") => Command::ExportMidi,
+        Some("import-midi") => Command::ImportMidi,
+        Some("check") => Command::Check,
+        Some("fmt") => Command::Fmt,
+        Some("-h" | "--help") => {
+            println!("{USAGE}");
+            std::process::exit(0);
+        }
+        _ => fail(USAGE),
+    };
+    let mut args = Args {
+        command,
+        song_path: String::new(),
+        bpm: None,
+        key: None,
+        scale: None,
+        start: None,
+        end: None,
+        device: None,
+        out_path: None,
+        format: lib::WavFormat::Int16,
+        sample_rate: None,
+    };
+    let mut song_path = None;
+    while let Some(arg) = argv.next() {
+        let mut value = || {
+            argv.next()
+                .unwrap_or_else(|| fail(&format!("{arg} needs a value")))
+        };
+        match arg.as_str() {
+            "--bpm" => {
+                let bpm = value();
+                let parsed = bpm.parse().ok().filter(|&bpm| bpm > 0);
+                args.bpm = Some(parsed.unwrap_or_else(|| fail(&format!("bad bpm: {bpm}"))));
+            }
+            "--key" => {
+                let key = value();
+                let parsed = lib::grammar::key_name(&key);
+                args.key = Some(parsed.unwrap_or_else(|_| fail(&format!("bad key: {key}"))));
+            }
+            "--scale" => {
+                let scale = value();
+                let parsed = dsl::Scale::from_name(&scale);
+                args.scale = Some(parsed.unwrap_or_else(|| fail(&format!("bad scale: {scale}"))));
+            }
+            "--start" => args.start = Some(value()),
+            "--end" => args.end = Some(value()),
+            "--device" => args.device = Some(value()),
+            "-o" | "--output" => args.out_path = Some(value()),
+            "--format" => {
+                args.format = match value().as_str() {
+                    "s16" => lib::WavFormat::Int16,
+                    "f32" => lib::WavFormat::Float32,
+                    format => fail(&format!("unknown format: {format}")),
+                }
+            }
+            "--sample-rate" => {
+                let rate = value();
+                let parsed = rate.parse().ok().filter(|&rate| rate > 0);
+                args.sample_rate =
+                    Some(parsed.unwrap_or_else(|| fail(&format!("bad sample rate: {rate}"))));
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            path if song_path.is_none() && (path == "-" || !path.starts_with('-')) => {
+                song_path = Some(arg)
+            }
+            _ => fail(USAGE),
+        }
+    }
+    args.song_path = song_path.unwrap_or_else(|| fail(USAGE));
+    if command == Command::Render && args.out_path.is_none() {
+        fail("render needs an output path, pass -o <out.wav>");
+    }
+    if command == Command::ExportMidi && args.out_path.is_none() {
+        fail("export-midi needs an output path, pass -o <out.mid>");
+    }
+    args
+}
+
+fn import_midi(args: &Args) {
+    let path = &args.song_path;
+    let bytes = if path == "-" {
+        let mut bytes = vec![];
+        std::io::stdin()
+            .read_to_end(&mut bytes)
+            .unwrap_or_else(|e| fail(&format!("couldn't read stdin: {e}")));
+        bytes
+    } else {
+        std::fs::read(path).unwrap_or_else(|e| fail(&format!("couldn't read {path}: {e}")))
+    };
+    let song = lib::import_midi(&bytes, args.key, args.scale)
+        .unwrap_or_else(|e| fail(&format!("couldn't import {path}: {e}")));
+    match &args.out_path {
+        Some(out_path) => std::fs::write(out_path, song)
+            .unwrap_or_else(|e| fail(&format!("couldn't write {out_path}: {e}"))),
+        None => print!("{song}"),
+    }
+}
+
+// returns a name to show in error messages along with the song itself
+fn read_song(path: &str) -> (String, String) {
+    if path == "-" {
+        let mut song_text = String::new();
+        std::io::stdin()
+            .read_to_string(&mut song_text)
+            .unwrap_or_else(|e| fail(&format!("couldn't read stdin: {e}")));
+        ("<stdin>".to_string(), song_text)
+    } else {
+        let song_text = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| fail(&format!("couldn't read {path}: {e}")));
+        (path.to_string(), song_text)
+    }
+}
+
+// positions are either 1-based line:col like editors show, or a byte offset
+fn position(song_text: &str, pos: &str) -> usize {
+    let bad_position = || fail(&format!("bad position: {pos}"));
+    let offset = match pos.split_once(':') {
+        Some((line, col)) => {
+            let line: usize = line.parse().unwrap_or_else(|_| bad_position());
+            let col: usize = col.parse().unwrap_or_else(|_| bad_position());
+            let line_start: usize = song_text
+                .split_inclusive('\n')
+                .take(line.saturating_sub(1))
+                .map(str::len)
+                .sum();
+            line_start + col.saturating_sub(1)
+        }
+        None => pos.parse().unwrap_or_else(|_| bad_position()),
+    };
+    offset.min(song_text.len())
+}
+
+fn play(
+    ctx: &mut lib::SongContext,
+    device: Option<&str>,
+    mut next_samples: impl FnMut(&mut lib::SongContext) -> Vec<f32>,
+) {
+    let pulse = init_pulse(device, ctx.sample_rate());
     let mut buffer = [0f32; BUFFER_SIZE];
-    let song = song
-        .into_iter()
-        .map(|spanned| spanned.instruction)
-        .collect::<Vec<_>>();
-    let mut ctx = lib::SongContext::default(song.clone());
-    for chunk in ctx.play(&song).array_chunks::<BUFFER_SIZE_HALF>() {
-        for (i, &note) in chunk.iter().enumerate() {
-            buffer[i * 2] = note;
-            buffer[i * 2 + 1] = note;
+    let mut pending = vec![];
+    while !ctx.is_done() {
+        pending.extend(next_samples(ctx));
+        while pending.len() >= BUFFER_SIZE_HALF {
+            write_stereo(&pulse, &mut buffer, &pending[..BUFFER_SIZE_HALF]);
+            pending.drain(..BUFFER_SIZE_HALF);
         }
-        pulse.write(as_u8_slice(&buffer)).unwrap();
     }
+    pending.resize(BUFFER_SIZE_HALF, 0.);
+    write_stereo(&pulse, &mut buffer, &pending);
+    pulse.drain().unwrap();
+}
+
+fn write_stereo(pulse: &Simple, buffer: &mut [f32; BUFFER_SIZE], chunk: &[f32]) {
+    for (i, &note) in chunk.iter().enumerate() {
+        buffer[i * 2] = note;
+        buffer[i * 2 + 1] = note;
+    }
+    pulse.write(as_u8_slice(buffer)).unwrap();
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{msg}");
+    std::process::exit(1)
 }
 
 fn as_u8_slice<T>(input: &[T]) -> &[u8] {
@@ -55,11 +351,11 @@ fn as_u8_slice<T>(input: &[T]) -> &[u8] {
     }
 }
 
-fn init_pulse() -> Simple {
+fn init_pulse(device: Option<&str>, sample_rate: u32) -> Simple {
     let spec = Spec {
         format: Format::F32le,
         channels: 2,
-        rate: lib::SAMPLE_RATE as _,
+        rate: sample_rate,
     };
     assert!(spec.is_valid());
 
@@ -67,12 +363,12 @@ fn init_pulse() -> Simple {
         None,                // Use the default server
         "reject synth",      // Our application’s name
         Direction::Playback, // We want a playback stream
-        None,                // Use the default device
+        device,              // Or None for the default device
         "synth",             // Description of our stream
         &spec,               // Our sample format
         None,                // Use default channel map
         None,                // Use default buffering attributes
     )
-    .unwrap();
+    .unwrap_or_else(|e| fail(&format!("couldn't connect to PulseAudio: {e}")));
     s
 }
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use dsl::{Instruction, TimeSignature, VoiceName};
+
+use crate::{RejectError, SpannedInstruction};
+
+/// A run of notes and rests in one voice that ends in a comma or a bar line,
+/// so that whatever's drawing or laying out the song can line voices up by
+/// their beats.
+#[derive(Debug, Clone)]
+pub struct BeatGroup {
+    pub voice: Option<VoiceName>,
+    // from the first note to the comma or bar line
+    pub l: usize,
+    pub r: usize,
+    // how far into the voice it starts
+    pub start: Beats,
+    pub length: Beats,
+}
+
+/// Warns about every bar (everything up to a `|`) that doesn't add up to the
+/// length of a bar in its voice's time signature, and in strict mode, every
+/// group of notes up to a comma or `|` that doesn't add up to a beat. Voices
+/// count separately, and a voice starts out in whatever time signature and
+/// mode the song's in where the voice first shows up. Whatever's after the
+/// last `|` or comma doesn't get checked, so songs can end on a short bar (or
+/// not use bar lines at all).
+pub(crate) fn check_song(
+    src: &str,
+    song: &[SpannedInstruction],
+) -> (Vec<RejectError>, Vec<BeatGroup>) {
+    let mut diagnostics = vec![];
+    let mut groups = vec![];
+    let mut voices: HashMap<Option<VoiceName>, VoiceState> = HashMap::new();
+    let mut voice = None;
+    let mut main_meter = Meter::default();
+    for spanned in song {
+        match spanned.instruction {
+            Instruction::BeginVoice(name) => voice = Some(name),
+            Instruction::EndVoice => voice = None,
+            _ => {}
+        }
+        let state = voices
+            .entry(voice)
+            .or_insert_with(|| VoiceState::new(main_meter));
+        match spanned.instruction {
+            Instruction::SetTimeSignature(time) => state.meter.time = time,
+            Instruction::SetStrict(group) => state.meter.strict = Some(group),
+            Instruction::PlayNote(dsl::Note { duration, .. })
+            | Instruction::PlayRest(dsl::Rest { duration, .. }) => {
+                state.bar.add(spanned.l, state.position, duration);
+                state.group.add(spanned.l, state.position, duration);
+                state.position += duration;
+            }
+            // so a bar or group that starts with a tuplet starts at its `3:(`
+            Instruction::BeginTuplet(_) => {
+                state.bar.start(spanned.l, state.position);
+                state.group.start(spanned.l, state.position);
+            }
+            Instruction::BarLine => {
+                let bar = std::mem::take(&mut state.bar);
+                let expected = bar_length(state.meter.time);
+                match bar.l {
+                    Some(l) if bar.length != expected => {
+                        let TimeSignature { beats, beat_unit } = state.meter.time;
+                        let message = format!(
+                            "this bar is {} long, but bars in {beats}/{beat_unit} are {}",
+                            bar.length.with_unit(),
+                            expected.with_unit(),
+                        );
+                        diagnostics.push(RejectError::warning(src, l, spanned.r, message));
+                    }
+                    // a bar line at the start, or two in a row, has no bar
+                    // before it to check
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        if voice.is_none() {
+            main_meter = state.meter;
+        }
+        if matches!(
+            spanned.instruction,
+            Instruction::BeatBoundary | Instruction::BarLine
+        ) {
+            let group = std::mem::take(&mut state.group);
+            // a comma right after a bar line, or after a harmony, has nothing
+            // to group
+            let Some(l) = group.l else {
+                continue;
+            };
+            if let Some(expected) = state.meter.group_length() {
+                if group.length != expected {
+                    let message = format!(
+                        "this group is {} long, but in strict mode the groups between commas are {}",
+                        group.length.with_unit(),
+                        expected.with_unit(),
+                    );
+                    diagnostics.push(RejectError::warning(src, l, spanned.r, message));
+                }
+            }
+            groups.push(BeatGroup {
+                voice,
+                l,
+                r: spanned.r,
+                start: group.start,
+                length: group.length,
+            });
+        }
+    }
+    (diagnostics, groups)
+}
+
+#[derive(Clone, Copy)]
+struct Meter {
+    time: TimeSignature,
+    // Some when it's in strict mode, with what each group has to last as
+    // long as a bar of, if it's not just a beat
+    strict: Option<Option<TimeSignature>>,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self {
+            time: TimeSignature::COMMON,
+            strict: None,
+        }
+    }
+}
+
+impl Meter {
+    fn group_length(&self) -> Option<Beats> {
+        Some(match self.strict? {
+            Some(group) => bar_length(group),
+            // beats are whatever the time signature says they are, e.g.
+            // eighth notes in 6/8
+            None => Beats::new(4, self.time.beat_unit as u64),
+        })
+    }
+}
+
+fn bar_length(time: TimeSignature) -> Beats {
+    let (numerator, denominator) = time.bar_length();
+    Beats::new(numerator as u64, denominator as u64)
+}
+
+struct VoiceState {
+    meter: Meter,
+    position: Beats,
+    bar: Run,
+    group: Run,
+}
+
+impl VoiceState {
+    fn new(meter: Meter) -> Self {
+        Self {
+            meter,
+            position: Beats::ZERO,
+            bar: Run::default(),
+            group: Run::default(),
+        }
+    }
+}
+
+// the notes in a bar or group so far
+#[derive(Default)]
+struct Run {
+    // where its first note, or the tuplet it starts with, is
+    l: Option<usize>,
+    start: Beats,
+    length: Beats,
+}
+
+impl Run {
+    fn start(&mut self, l: usize, position: Beats) {
+        if self.l.is_none() {
+            self.l = Some(l);
+            self.start = position;
+        }
+    }
+
+    fn add(&mut self, l: usize, position: Beats, duration: dsl::Duration) {
+        self.start(l, position);
+        self.length += duration;
+    }
+}
+
+/// A length of time in beats (quarter notes), kept as a fraction so that
+/// triplets and dotted notes add up exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Beats {
+    numerator: u64,
+    denominator: u64,
+}
+
+impl Default for Beats {
+    fn default() -> Self {
+        Self::ZERO
+    }
+}
+
+impl Beats {
+    pub const ZERO: Self = Self {
+        numerator: 0,
+        denominator: 1,
+    };
+
+    pub fn new(numerator: u64, denominator: u64) -> Self {
+        let gcd = gcd(numerator, denominator);
+        Self {
+            numerator: numerator / gcd,
+            denominator: denominator / gcd,
+        }
+    }
+
+    pub fn numerator(&self) -> u64 {
+        self.numerator
+    }
+
+    pub fn denominator(&self) -> u64 {
+        self.denominator
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.numerator as f64 / self.denominator as f64
+    }
+
+    pub fn is_whole(&self) -> bool {
+        self.numerator.is_multiple_of(self.denominator)
+    }
+
+    // "1/2 beat", "1 beat", "2 1/2 beats"
+    fn with_unit(self) -> String {
+        let plural = if self.numerator > self.denominator {
+            "s"
+        } else {
+            ""
+        };
+        format!("{self} beat{plural}")
+    }
+}
+
+impl std::ops::AddAssign<dsl::Duration> for Beats {
+    fn add_assign(&mut self, duration: dsl::Duration) {
+        let (numerator, denominator) = (duration.numerator as u64, duration.denominator as u64);
+        *self = Beats::new(
+            self.numerator * denominator + numerator * self.denominator,
+            self.denominator * denominator,
+        );
+    }
+}
+
+// as a mixed number, like 3 1/2
+impl fmt::Display for Beats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (whole, numerator) = (
+            self.numerator / self.denominator,
+            self.numerator % self.denominator,
+        );
+        match (whole, numerator) {
+            (whole, 0) => write!(f, "{whole}"),
+            (0, numerator) => write!(f, "{numerator}/{}", self.denominator),
+            (whole, numerator) => write!(f, "{whole} {numerator}/{}", self.denominator),
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
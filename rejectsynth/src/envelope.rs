@@ -0,0 +1,46 @@
+use dsl::{Curve, Envelope};
+
+use crate::ms_to_samples;
+
+/// How loud a note is `i` samples after it started, between 0 and 1.
+/// `released_at` is when the note let go, if it has, and from then on it fades
+/// out from wherever it got to.
+pub(crate) fn level(
+    envelope: &Envelope,
+    i: usize,
+    released_at: Option<usize>,
+    sample_rate: u32,
+) -> f32 {
+    match released_at {
+        Some(released_at) if i >= released_at => {
+            let num_samples_in_release =
+                ms_to_samples(envelope.release_ms as usize, sample_rate).max(1);
+            let x = (i - released_at) as f32 / num_samples_in_release as f32;
+            held_level(envelope, released_at, sample_rate) * (1. - shape(envelope.curve, x.min(1.)))
+        }
+        _ => held_level(envelope, i, sample_rate),
+    }
+}
+
+// attack up to full volume, then decay down to the sustain level
+fn held_level(envelope: &Envelope, i: usize, sample_rate: u32) -> f32 {
+    let num_samples_in_attack = ms_to_samples(envelope.attack_ms as usize, sample_rate);
+    let num_samples_in_decay = ms_to_samples(envelope.decay_ms as usize, sample_rate);
+    if i < num_samples_in_attack {
+        shape(envelope.curve, i as f32 / num_samples_in_attack as f32)
+    } else if i - num_samples_in_attack < num_samples_in_decay {
+        let x = (i - num_samples_in_attack) as f32 / num_samples_in_decay as f32;
+        1. - (1. - envelope.sustain) * shape(envelope.curve, x)
+    } else {
+        envelope.sustain
+    }
+}
+
+// how far along a segment of the envelope we are, `x` going from 0 to 1. the
+// exponential curve covers most of the distance early on and then eases in
+fn shape(curve: Curve, x: f32) -> f32 {
+    match curve {
+        Curve::Linear => x,
+        Curve::Exponential => 1. - (1. - x).powi(3),
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct RejectError {
+    pub l: usize,
+    pub r: usize,
+    pub line_no: usize,
+    pub col_no: usize,
+    // warnings don't stop the song from playing, they're things like bars
+    // that are the wrong length
+    pub is_warning: bool,
+    expected: Vec<String>,
+    message: String,
+}
+
+impl RejectError {
+    pub fn new(src: &str, l: usize, r: usize, message: impl Into<String>) -> Self {
+        let (line_no, col_no) = line_col(src, l);
+        Self {
+            l,
+            r,
+            line_no,
+            col_no,
+            is_warning: false,
+            expected: vec![],
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(src: &str, l: usize, r: usize, message: impl Into<String>) -> Self {
+        Self {
+            is_warning: true,
+            ..Self::new(src, l, r, message)
+        }
+    }
+
+    pub fn from_peg(src: &str, err: peg::error::ParseError<peg::str::LineCol>) -> Self {
+        let l = err.location.offset;
+        let (r, message) = match src[l..].chars().next() {
+            Some(c) => (
+                l + c.len_utf8(),
+                format!("unexpected `{}`", c.escape_debug()),
+            ),
+            None => (l, "unexpected end of song".to_string()),
+        };
+        let mut expected = err
+            .expected
+            .tokens()
+            .map(|token| token.to_string())
+            .collect::<Vec<_>>();
+        expected.sort();
+        Self {
+            expected,
+            ..Self::new(src, l, r, message)
+        }
+    }
+
+    pub fn expected_tokens(&self) -> &[String] {
+        &self.expected
+    }
+}
+
+#[wasm_bindgen]
+impl RejectError {
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+
+    // joined with ", " because that's how they end up getting shown anyway
+    #[wasm_bindgen(getter)]
+    pub fn expected(&self) -> String {
+        self.expected.join(", ")
+    }
+
+    // the message with what was expected after it, if anything was
+    #[wasm_bindgen(getter)]
+    pub fn full_message(&self) -> String {
+        self.to_string()
+    }
+
+    /// Renders the error (or warning) rustc-style: the message, where it happened, then the
+    /// offending line of `src` with carets under the span.
+    #[wasm_bindgen]
+    pub fn render(&self, src: &str, origin: &str) -> String {
+        let line = src.lines().nth(self.line_no).unwrap_or("");
+        let line_no = (self.line_no + 1).to_string();
+        let gutter = " ".repeat(line_no.len());
+        // spans can run past the end of the line (or be empty at the end of the
+        // song), but there should always be at least one caret to look at
+        let num_carets = (self.r - self.l)
+            .min(line.len().saturating_sub(self.col_no))
+            .max(1);
+        format!(
+            "{}: {self}\n{gutter}--> {origin}:{}:{}\n{gutter} |\n{line_no} | {line}\n{gutter} | {}{}\n",
+            if self.is_warning { "warning" } else { "error" },
+            self.line_no + 1,
+            self.col_no + 1,
+            " ".repeat(self.col_no),
+            "^".repeat(num_carets),
+        )
+    }
+}
+
+impl fmt::Display for RejectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        match self.expected.as_slice() {
+            [] => Ok(()),
+            [only] => write!(f, ", expected {only}"),
+            expected => write!(f, ", expected one of {}", expected.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for RejectError {}
+
+/// Zero-based (line, column) of a byte offset, the same way the editor counts.
+pub fn line_col(src: &str, pos: usize) -> (usize, usize) {
+    let before = &src[..pos];
+    let line_no = before.matches('\n').count();
+    let col_no = match before.rfind('\n') {
+        Some(i) => pos - i - 1,
+        None => pos,
+    };
+    (line_no, col_no)
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use dsl::{Instruction, VoiceName};
+
+use crate::check::Beats;
+use crate::{parse, RejectError};
+
+/// Lays the song out the same way every time: one space between things on a
+/// line, commas stuck to whatever's before them, voices indented, never more
+/// than one blank line in a row, and the harmony labels that start the lines
+/// of a paragraph padded so the notes after them line up. On lines that split
+/// beats into shorter notes, beats that don't already end in a comma get an
+/// extra space after them. Line breaks and comments stay where they were, and
+/// the song parses into exactly the same instructions as before.
+pub fn format_song(src: &str) -> Result<String, RejectError> {
+    let parse_result = parse(src)?;
+    let mut items = parse_result
+        .spanned_instructions
+        .iter()
+        .map(|spanned| (spanned.range(), Some(spanned.instruction)))
+        .collect::<Vec<_>>();
+    for comment in parse_result.comments {
+        // comments inside an instruction (`bpm /* fast */ 180`) stay part of it
+        let inside = items
+            .iter()
+            .any(|(range, _)| range.start <= comment.start && comment.end <= range.end);
+        if !inside {
+            items.push((comment, None));
+        }
+    }
+    items.sort_by_key(|(range, _)| range.start);
+
+    // None is a blank line
+    let mut lines: Vec<Option<Line>> = vec![];
+    let mut line = Line::default();
+    let mut depth = 0;
+    let mut voice = None;
+    // same-named voices share a timeline, and the main voice is None
+    let mut beats: HashMap<Option<VoiceName>, Beats> = HashMap::new();
+    let mut prev_end = 0;
+    for (range, instruction) in items {
+        let gap = &src[prev_end..range.start];
+        prev_end = range.end;
+        let newlines = gap.matches('\n').count();
+        if newlines > 0 && !line.words.is_empty() {
+            lines.push(Some(std::mem::take(&mut line)));
+            if newlines > 1 {
+                lines.push(None);
+            }
+        }
+        // commas stick to whatever's before them, even if that's on the line
+        // before
+        if let Some(Instruction::BeatBoundary) = instruction {
+            let prev = line
+                .words
+                .iter_mut()
+                .rev()
+                .chain(
+                    lines
+                        .iter_mut()
+                        .rev()
+                        .flatten()
+                        .flat_map(|line| line.words.iter_mut().rev()),
+                )
+                .find(|word| word.instruction.is_some());
+            if let Some(prev) = prev {
+                prev.comma = true;
+            }
+            continue;
+        }
+
+        let text = &src[range.clone()];
+        let text = match instruction {
+            // a line comment runs to the end of the line, spaces and all
+            None => text.trim_end().to_string(),
+            // anything with a comment in it stays just how it was, so the
+            // comment doesn't get lost
+            Some(_) if text.contains("//") || text.contains("/*") => text.to_string(),
+            Some(Instruction::BeginVoice(name)) => format!("voice {} {{", name.as_str()),
+            Some(_) => text.split_whitespace().collect::<Vec<_>>().join(" "),
+        };
+        if line.words.is_empty() {
+            let is_end = matches!(instruction, Some(Instruction::EndVoice));
+            line.depth = if is_end { depth - 1 } else { depth };
+        }
+        let mut word = Word {
+            text,
+            instruction,
+            comma: false,
+            short: false,
+            on_beat: false,
+        };
+        match instruction {
+            Some(Instruction::BeginVoice(name)) => {
+                depth += 1;
+                voice = Some(name);
+            }
+            Some(Instruction::EndVoice) => {
+                depth -= 1;
+                voice = None;
+            }
+            Some(Instruction::PlayNote(dsl::Note { duration, .. }))
+            | Some(Instruction::PlayRest(dsl::Rest { duration, .. })) => {
+                let beat = beats.entry(voice).or_insert(Beats::ZERO);
+                *beat += duration;
+                word.short = duration.numerator < duration.denominator;
+                word.on_beat = beat.is_whole();
+            }
+            // the space after a beat goes after the tuplet it finishes instead
+            Some(Instruction::EndTuplet) => {
+                word.on_beat = line.words.last().is_some_and(|prev| prev.on_beat);
+            }
+            _ => {}
+        }
+        line.words.push(word);
+    }
+    if !line.words.is_empty() {
+        lines.push(Some(line));
+    }
+
+    let mut out = String::new();
+    for paragraph in lines.split(|line| line.is_none()) {
+        let labels = paragraph
+            .iter()
+            .flatten()
+            .filter_map(|line| line.label())
+            .map(|label| label.text.len())
+            .collect::<Vec<_>>();
+        let label_width = if labels.len() > 1 {
+            labels.into_iter().max()
+        } else {
+            None
+        };
+        for line in paragraph.iter().flatten() {
+            line.write(&mut out, label_width);
+        }
+        out.push('\n');
+    }
+    // there's one blank line too many after the last paragraph
+    out.pop();
+    Ok(out)
+}
+
+#[derive(Default)]
+struct Line {
+    // how many voices in it is
+    depth: usize,
+    words: Vec<Word>,
+}
+
+struct Word {
+    text: String,
+    // None for comments
+    instruction: Option<Instruction>,
+    comma: bool,
+    // a note or rest shorter than a beat
+    short: bool,
+    // a note or rest that takes its voice up to the end of a beat
+    on_beat: bool,
+}
+
+impl Word {
+    // bar lines and the ends of tuplets don't get a gap before them
+    fn is_closing(&self) -> bool {
+        matches!(
+            self.instruction,
+            Some(Instruction::BarLine | Instruction::EndTuplet)
+        )
+    }
+}
+
+impl Line {
+    // the harmony the line starts with, as long as there's more after it
+    fn label(&self) -> Option<&Word> {
+        match self.words.as_slice() {
+            [label @ Word {
+                instruction: Some(Instruction::SetHarmony(_)),
+                ..
+            }, next, ..]
+                if next.instruction.is_some() =>
+            {
+                Some(label)
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&self, out: &mut String, label_width: Option<usize>) {
+        let is_split_into_beats = self.words.iter().any(|word| word.short);
+        let label = self.label();
+        out.push_str(&"    ".repeat(self.depth));
+        for (i, word) in self.words.iter().enumerate() {
+            if i > 0 {
+                let prev = &self.words[i - 1];
+                let spaces = match label_width {
+                    Some(width) if i == 1 && label.is_some() => width - prev.text.len() + 1,
+                    _ if is_split_into_beats
+                        && prev.on_beat
+                        && !prev.comma
+                        && !word.is_closing() =>
+                    {
+                        2
+                    }
+                    _ => 1,
+                };
+                out.push_str(&" ".repeat(spaces));
+            }
+            out.push_str(&word.text);
+            if word.comma {
+                out.push(',');
+            }
+        }
+        out.push('\n');
+    }
+}
@@ -1,17 +1,32 @@
+mod check;
+mod envelope;
+mod error;
+mod format;
+mod midi;
+mod mixer;
+mod oscillator;
 mod parser;
+mod voice;
+mod wav;
 
 use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::ops::{Bound, Range, RangeInclusive};
 
-use dsl::{Accidental, Harmony, Instruction, Key, Note, NotePitch, Scale, ABC};
+use dsl::{Accidental, Channel, Envelope, Instruction, Key, Scale, Waveform, ABC};
 use r#macro::m;
+use voice::Voice;
 use wasm_bindgen::prelude::wasm_bindgen;
 
-pub const SAMPLE_RATE: f32 = 44100.0; // 44.1 kHz
+// what songs render at unless they're told otherwise
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100; // 44.1 kHz
 
-use crate::parser::SpannedInstruction;
-pub use parser::grammar;
+pub use check::{BeatGroup, Beats};
+pub use error::RejectError;
+pub use format::format_song;
+pub use midi::{import_midi, write_midi};
+pub use parser::{grammar, SpannedInstruction};
+pub use wav::{write_wav, WavFormat};
 
 #[wasm_bindgen]
 pub struct WasmSongIterator {
@@ -24,13 +39,14 @@ pub struct WasmSongIterator {
 #[wasm_bindgen]
 impl WasmSongIterator {
     #[wasm_bindgen]
-    pub fn from_song_text(song_text: &str, l: Option<usize>, r: Option<usize>) -> Self {
-        let parse_result = parse(song_text).unwrap();
-        let instructions = parse_result
-            .spanned_instructions
-            .iter()
-            .map(|spanned_instruction| spanned_instruction.instruction)
-            .collect();
+    pub fn from_song_text(
+        song_text: &str,
+        l: Option<usize>,
+        r: Option<usize>,
+        sample_rate: Option<u32>,
+    ) -> Result<WasmSongIterator, RejectError> {
+        let parse_result = parse(song_text)?;
+        let instructions = parse_result.instructions();
         let selection = match (l, r) {
             // TODO: this is weird, it works better when i subtract 1,
             // BUT the indices from vs code seem to be all right
@@ -38,12 +54,16 @@ impl WasmSongIterator {
             (Some(l), Some(r)) => Some(l..=r),
             _ => None,
         };
-        Self {
-            ctx: SongContext::default(instructions),
+        let mut ctx = SongContext::default(instructions);
+        if let Some(sample_rate) = sample_rate.filter(|&rate| rate > 0) {
+            ctx.set_sample_rate(sample_rate);
+        }
+        Ok(Self {
+            ctx,
             song: parse_result.spanned_instructions,
             syntaxes: parse_result.syntaxes,
             selection,
-        }
+        })
     }
 
     #[wasm_bindgen]
@@ -53,25 +73,9 @@ impl WasmSongIterator {
 
     #[wasm_bindgen]
     pub fn play_next(&mut self) -> PlaybackResult {
-        let samples = match self.ctx.current_instruction() {
-            Instruction::PlayNote { .. } => {
-                let spanned_instruction = &self.song[self.ctx.pc];
-                if let Some(selection) = &self.selection {
-                    if ranges_intersect(selection.clone(), spanned_instruction.range()) {
-                        self.ctx.iterate()
-                    } else {
-                        self.ctx.skip();
-                        vec![]
-                    }
-                } else {
-                    self.ctx.iterate()
-                }
-            }
-            Instruction::SetBPM(_)
-            | Instruction::SetKey(_)
-            | Instruction::SetScale(_)
-            | Instruction::SkipToNote
-            | Instruction::SetHarmony(_) => self.ctx.iterate(),
+        let samples = match &self.selection {
+            Some(selection) => self.ctx.iterate_selection(&self.song, selection),
+            None => self.ctx.iterate(),
         };
 
         let on_syntaxes = self
@@ -129,6 +133,40 @@ impl Syntax {
     }
 }
 
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct NoteDescription {
+    // in bytes, like the offset it was asked for
+    pub l: usize,
+    pub r: usize,
+    // these are None for rests
+    name: Option<String>,
+    freq: Option<f32>,
+    pub beats: f32,
+    pub duration_ms: f32,
+    // whatever chord the harmony's playing underneath, if there is one
+    chord: Vec<String>,
+}
+
+#[wasm_bindgen]
+impl NoteDescription {
+    /// The note's name and octave, e.g. C#4 / Db4.
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn freq(&self) -> Option<f32> {
+        self.freq
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn chord(&self) -> Vec<String> {
+        self.chord.clone()
+    }
+}
+
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
@@ -160,14 +198,29 @@ fn console_error_panic_hook(info: &std::panic::PanicInfo) {
     log(&description);
 }
 
-struct ParseResult {
-    spanned_instructions: Vec<SpannedInstruction>,
-    syntaxes: Vec<Syntax>,
+pub struct ParseResult {
+    pub spanned_instructions: Vec<SpannedInstruction>,
+    // one per instruction, in the same order
+    pub syntaxes: Vec<Syntax>,
+    pub comments: Vec<Range<usize>>,
+    // warnings about a song that parsed fine
+    pub diagnostics: Vec<RejectError>,
+    // the notes between each comma or bar line and the one before, in order
+    pub beat_groups: Vec<BeatGroup>,
+}
+
+impl ParseResult {
+    pub fn instructions(&self) -> Vec<Instruction> {
+        self.spanned_instructions
+            .iter()
+            .map(|spanned_instruction| spanned_instruction.instruction)
+            .collect()
+    }
 }
 
-fn parse(s: &str) -> Result<ParseResult, Box<dyn std::error::Error>> {
+pub fn parse(s: &str) -> Result<ParseResult, RejectError> {
     let positions_of_line_breaks = s.match_indices('\n').map(|(i, _)| i).collect::<Vec<_>>();
-    let spanned_instructions = grammar::song(s)?;
+    let spanned_instructions = grammar::song(s).map_err(|e| RejectError::from_peg(s, e))?;
 
     let syntaxes = spanned_instructions
         .iter()
@@ -189,8 +242,22 @@ fn parse(s: &str) -> Result<ParseResult, Box<dyn std::error::Error>> {
                 Instruction::SetKey(_) => "SetKey",
                 Instruction::SetScale(_) => "SetScale",
                 Instruction::PlayNote { .. } => "PlayNote",
+                Instruction::PlayRest(_) => "PlayRest",
                 Instruction::SkipToNote => "SkipToNote",
                 Instruction::SetHarmony(_) => "SetHarmony",
+                Instruction::BeginVoice(_) => "BeginVoice",
+                Instruction::EndVoice => "EndVoice",
+                Instruction::SetOctave(_) => "SetOctave",
+                Instruction::SetVolume(_) => "SetVolume",
+                Instruction::SetWaveform(..) => "SetWaveform",
+                Instruction::SetEnvelope(_) => "SetEnvelope",
+                Instruction::SetGain(..) => "SetGain",
+                Instruction::SetTimeSignature(_) => "SetTimeSignature",
+                Instruction::BarLine => "BarLine",
+                Instruction::BeatBoundary => "BeatBoundary",
+                Instruction::SetStrict(_) => "SetStrict",
+                Instruction::BeginTuplet(_) => "BeginTuplet",
+                Instruction::EndTuplet => "EndTuplet",
             }
             .to_string();
             Syntax {
@@ -201,52 +268,138 @@ fn parse(s: &str) -> Result<ParseResult, Box<dyn std::error::Error>> {
             }
         })
         .collect();
+    let comments = grammar::comments(s).map_err(|e| RejectError::from_peg(s, e))?;
+    let (diagnostics, beat_groups) = check::check_song(s, &spanned_instructions);
     Ok(ParseResult {
         spanned_instructions,
         syntaxes,
+        comments,
+        diagnostics,
+        beat_groups,
     })
 }
 
 #[wasm_bindgen]
-pub fn syntax(s: &str) -> Vec<Syntax> {
-    parse(s).unwrap().syntaxes
+pub fn syntax(s: &str) -> Result<Vec<Syntax>, RejectError> {
+    let parse_result = parse(s)?;
+    let instructions = parse_result
+        .spanned_instructions
+        .iter()
+        .zip(&parse_result.syntaxes)
+        .map(|(spanned, syntax)| (spanned.range(), syntax.node_type.as_str()));
+    // comments inside an instruction (`bpm /* fast */ 180`) are part of its
+    // token, editors don't want tokens on top of each other
+    let comments = parse_result
+        .comments
+        .iter()
+        .filter(|comment| {
+            let mut spans = parse_result.spanned_instructions.iter();
+            !spans.any(|spanned| spanned.l <= comment.start && comment.end <= spanned.r)
+        })
+        .map(|comment| (comment.clone(), "Comment"));
+
+    let mut syntaxes = vec![];
+    for (range, node_type) in instructions.chain(comments) {
+        // editors want a separate token for each line of anything that goes
+        // over more than one, like a block comment
+        let mut l = range.start;
+        for line in s[range].split_inclusive('\n') {
+            let indent = line.len() - line.trim_start().len();
+            let len = line.trim().len();
+            if len > 0 {
+                let (line_no, col_no) = error::line_col(s, l + indent);
+                syntaxes.push(Syntax {
+                    line_no,
+                    col_no,
+                    len,
+                    node_type: node_type.to_string(),
+                });
+            }
+            l += line.len();
+        }
+    }
+    syntaxes.sort_by_key(|syntax| (syntax.line_no, syntax.col_no));
+    Ok(syntaxes)
 }
 
+/// Warnings for a song that parses, e.g. bars that don't add up.
 #[wasm_bindgen]
-// pos is the position in the song_file to skip to...
-pub fn playback_for_note_input(song_file: &str, pos: usize) -> Vec<f32> {
-    let parse_result = parse(song_file);
-    if parse_result.is_err() {
-        return vec![];
-    }
-    let parse_result = parse_result.unwrap();
-    let instructions = parse_result
+pub fn diagnostics(s: &str) -> Result<Vec<RejectError>, RejectError> {
+    Ok(parse(s)?.diagnostics)
+}
+
+/// The note or rest at byte `offset` in the song, as it'll actually be played:
+/// the song's played up to it so the key, scale, octave, tempo and harmony are
+/// whatever they are by then. None when there isn't a note there.
+#[wasm_bindgen]
+pub fn describe_at(song_text: &str, offset: usize) -> Option<NoteDescription> {
+    let parse_result = parse(song_text).ok()?;
+    let index = parse_result
         .spanned_instructions
         .iter()
-        .map(|spanned_instruction| spanned_instruction.instruction)
-        .collect();
+        .position(|spanned| spanned.range().contains(&offset))?;
+    let instructions = parse_result.instructions();
+    let (pitch, duration) = match instructions[index] {
+        Instruction::PlayNote(note) => (Some(note.pitch), note.duration),
+        Instruction::PlayRest(rest) => (None, rest.duration),
+        _ => return None,
+    };
+
+    let mut ctx = SongContext::default(instructions.clone());
+    for voice in &mut ctx.voices {
+        while let Some(i) = voice.next_instruction_index() {
+            // the harmony has to be up to date, but the note hasn't played yet
+            if i == index {
+                let freq = pitch.map(|pitch| semitones_to_freq(voice.note_pitch(pitch)));
+                let chord = voice.chord_pitches().into_iter();
+                let chord = chord.map(|pitch| freq_to_abc(semitones_to_freq(pitch)));
+                let spanned = &parse_result.spanned_instructions[index];
+                return Some(NoteDescription {
+                    l: spanned.l,
+                    r: spanned.r,
+                    name: freq.map(freq_to_abc),
+                    freq,
+                    beats: duration.numerator as f32 / duration.denominator as f32,
+                    duration_ms: voice.duration_ms(duration),
+                    chord: chord.collect(),
+                });
+            }
+            voice.eval(&instructions, true);
+            voice.advance();
+        }
+    }
+    None
+}
+
+#[wasm_bindgen]
+// pos is the position in the song_file to skip to...
+pub fn playback_for_note_input(song_file: &str, pos: usize, sample_rate: Option<u32>) -> Vec<f32> {
+    let parse_result = match parse(song_file) {
+        Ok(parse_result) => parse_result,
+        Err(_) => return vec![],
+    };
+    let instructions = parse_result.instructions();
     let mut ctx = SongContext::default(instructions);
+    if let Some(sample_rate) = sample_rate.filter(|&rate| rate > 0) {
+        ctx.set_sample_rate(sample_rate);
+    }
+    // every other note gets skipped, so the other voices don't take up any
+    // time and whatever comes out of the mix is just this note
+    let mut samples = vec![];
     while !ctx.is_done() {
-        let cur_instruction = ctx.current_instruction();
-        match cur_instruction {
-            Instruction::PlayNote { .. } => {
+        match ctx.current_instruction() {
+            Some(Instruction::PlayNote(_) | Instruction::PlayRest(_)) => {
                 let spanned_instruction = &parse_result.spanned_instructions[ctx.pc];
                 if pos >= spanned_instruction.l && pos <= spanned_instruction.r {
-                    return ctx.iterate();
+                    samples.extend(ctx.iterate());
                 } else {
                     ctx.skip();
                 }
             }
-            Instruction::SetBPM(_)
-            | Instruction::SetKey(_)
-            | Instruction::SetScale(_)
-            | Instruction::SkipToNote
-            | Instruction::SetHarmony(_) => {
-                ctx.iterate();
-            }
+            _ => samples.extend(ctx.iterate()),
         }
     }
-    vec![]
+    samples
 }
 
 pub mod songs {
@@ -306,171 +459,354 @@ fn find_skip_to_index(instructions: &[Instruction]) -> Option<usize> {
         })
 }
 
-const ATTACK_MS: usize = 10;
+// how long a tied note takes to glide to its new volume
+const GLIDE_MS: usize = 10;
 
-// volume is between 0 and 1
-fn freqs_to_samples<'a>(
-    duration_ms: usize,
-    freqs: impl IntoIterator<Item = f32> + ExactSizeIterator,
-    volume: f32,
-    phase: f32,
-) -> (impl Iterator<Item = f32> + 'a, f32) {
-    let num_samples_per_note = (SAMPLE_RATE * duration_ms as f32 / 1000.0) as usize;
-    let num_samples_in_attack = (SAMPLE_RATE * ATTACK_MS as f32 / 1000.0) as usize;
-
-    let freqs_len = freqs.len();
-    // Create phase increments for each frequency in the chord
-    let phase_increments: Vec<f32> = freqs
-        .into_iter()
-        .map(|freq| 2.0 * std::f32::consts::PI * freq / SAMPLE_RATE)
-        .collect();
+fn ms_to_samples(ms: usize, sample_rate: u32) -> usize {
+    ms * sample_rate as usize / 1000
+}
 
-    // Initialize phases for each frequency in the chord
-    let mut phases: Vec<f32> = vec![phase; freqs_len];
+// an oscillator that carries on from note to note, with its phase in radians
+#[derive(Debug, Clone, Copy)]
+struct Tone {
+    freq: f32,
+    phase: f32,
+}
 
-    // Calculate the average phase increment for ending_phase calculation
-    let avg_phase_increment = phase_increments.iter().sum::<f32>() / freqs_len as f32;
+// what the tones get played with. volume is between 0 and 1
+#[derive(Debug, Clone, Copy)]
+struct Sound {
+    waveform: Waveform,
+    volume: f32,
+    envelope: Envelope,
+    sample_rate: u32,
+}
 
-    let ending_phase = phase + avg_phase_increment * num_samples_per_note as f32;
+// the envelope starts `env_start` samples in, for notes tied from the one
+// before. the release runs on past the end of the note, but the tones that
+// come back are where they are when the note ends
+fn freqs_to_samples<'a>(
+    num_samples_per_note: usize,
+    tones: Vec<Tone>,
+    sound: Sound,
+    env_start: usize,
+    release: bool,
+) -> (impl Iterator<Item = f32> + 'a, Vec<Tone>) {
+    let Sound {
+        waveform,
+        volume,
+        envelope,
+        sample_rate,
+    } = sound;
+    const TAU: f64 = std::f64::consts::TAU;
+    let released_at = release.then_some(env_start + num_samples_per_note);
+    let num_samples_in_release = match released_at {
+        Some(_) => ms_to_samples(envelope.release_ms as usize, sample_rate),
+        None => 0,
+    };
 
-    // Normalize phase to [0, 2π]
-    let ending_phase_normalized = ending_phase % (2.0 * std::f32::consts::PI);
+    let num_tones = tones.len();
+    let phase_increments: Vec<f64> = tones
+        .iter()
+        .map(|tone| TAU * tone.freq as f64 / sample_rate as f64)
+        .collect();
+    // every sample's phase is worked out from the start of the note rather
+    // than added up as we go, so the next note starts exactly where this
+    // one's phase says it should, however long the note is
+    let phase_at =
+        move |tone: &Tone, incr: f64, i: usize| (tone.phase as f64 + incr * i as f64) % TAU;
+    let ending_tones = tones
+        .iter()
+        .zip(&phase_increments)
+        .map(|(tone, &incr)| Tone {
+            freq: tone.freq,
+            phase: phase_at(tone, incr, num_samples_per_note) as f32,
+        })
+        .collect();
+    // noise doesn't care about phase, but every oscillator wants its own
+    let mut noises: Vec<u32> = tones
+        .iter()
+        .map(|tone| (tone.phase.to_bits() ^ tone.freq.to_bits()) | 1)
+        .collect();
 
-    let samples_iter = (0..num_samples_per_note).map(move |i| {
+    let num_samples = num_samples_per_note + num_samples_in_release;
+    let samples_iter = (0..num_samples).map(move |i| {
         let mut chord_sample: f32 = 0.0;
-
-        for (p, &incr) in phases.iter_mut().zip(&phase_increments) {
-            chord_sample += p.sin();
-
-            // // First harmonic
-            // chord_sample += 0.5 * (2.0 * *p).sin();
-            // // Second harmonic
-            // chord_sample += 0.25 * (3.0 * *p).sin();
-
-            *p += incr;
-            if *p >= 2.0 * std::f32::consts::PI {
-                *p -= 2.0 * std::f32::consts::PI;
-            }
+        for ((tone, &incr), noise) in tones.iter().zip(&phase_increments).zip(&mut noises) {
+            let t = phase_at(tone, incr, i) / TAU;
+            chord_sample += oscillator::sample(waveform, t as f32, (incr / TAU) as f32, noise);
         }
 
         // Average the sample value for all notes in the chord
-        let sample = chord_sample / freqs_len as f32;
+        let sample = chord_sample / num_tones.max(1) as f32;
 
-        let attack_envelope = (i as f32 / num_samples_in_attack as f32).min(1.);
-        let release_envelope =
-            ((num_samples_per_note - i) as f32 / num_samples_in_attack as f32).min(1.);
-
-        sample * volume * attack_envelope * release_envelope
+        sample * volume * envelope::level(&envelope, env_start + i, released_at, sample_rate)
     });
 
-    (samples_iter, ending_phase_normalized)
+    (samples_iter, ending_tones)
 }
 
-fn to_freq(abc: ABC, accidental: Accidental) -> f32 {
-    let abc = match abc {
-        ABC::A => 0,
-        ABC::B => 2,
-        ABC::C => 3,
-        ABC::D => 5,
-        ABC::E => 7,
-        ABC::F => 8,
-        ABC::G => 10,
-    };
-    let accidental = match accidental {
+// how far the key's tonic is above A4, ABC's discriminants are just that
+fn key_semitones(key: Key) -> i32 {
+    let accidental = match key.accidental {
         Accidental::Natural => 0,
         Accidental::Sharp => 1,
         Accidental::Flat => -1,
     };
-    let degree = abc + accidental;
-    440.0 * 2.0f32.powf(degree as f32 / 12.0)
+    key.abc as i32 + accidental
 }
 
-fn shift_up_by_interval(freq: f32, interval: i8) -> f32 {
-    freq * 2.0f32.powf(interval as f32 / 12.0)
+fn semitones_to_freq(semitones: i32) -> f32 {
+    440.0 * 2.0f32.powf(semitones as f32 / 12.0)
 }
 
-const fn scale_ascending(scale: Scale) -> [i8; 7] {
+// steps in semitones between each note of the scale, all the way up to the
+// octave. not every scale has 7 notes so degrees wrap by the scale's length
+fn scale_ascending(scale: &Scale) -> &[i8] {
     match scale {
-        Scale::Major => [2, 2, 1, 2, 2, 2, 1],
-        Scale::Minor => [2, 1, 2, 2, 1, 2, 2],
+        Scale::Major => &[2, 2, 1, 2, 2, 2, 1],
+        Scale::Minor => &[2, 1, 2, 2, 1, 2, 2],
+        Scale::Dorian => &[2, 1, 2, 2, 2, 1, 2],
+        Scale::Phrygian => &[1, 2, 2, 2, 1, 2, 2],
+        Scale::Lydian => &[2, 2, 2, 1, 2, 2, 1],
+        Scale::Mixolydian => &[2, 2, 1, 2, 2, 1, 2],
+        Scale::Locrian => &[1, 2, 2, 1, 2, 2, 2],
+        Scale::HarmonicMinor => &[2, 1, 2, 2, 1, 3, 1],
+        Scale::MelodicMinor => &[2, 1, 2, 2, 2, 2, 1],
+        Scale::MajorPentatonic => &[2, 2, 3, 2, 3],
+        Scale::MinorPentatonic => &[3, 2, 2, 3, 2],
+        Scale::Blues => &[3, 2, 1, 1, 3, 2],
+        Scale::Custom(custom) => custom.steps(),
     }
 }
 
-fn scale_degree_to_semitones(scale: Scale, degree: u8) -> i8 {
+// in i32, since a custom scale's steps can add up to a lot more than an octave
+fn scale_degree_to_semitones(scale: Scale, degree: u8) -> i32 {
     if degree == 0 {
         panic!("scale degree cannot be 0, it doesn't make sense")
     }
-    if degree == 1 {
-        return 0;
-    }
-    scale_ascending(scale)
-        .iter()
-        .cycle()
-        .take((degree - 1) as usize)
-        .sum()
+    let steps = scale_ascending(&scale);
+    let sum = |steps: &[i8]| steps.iter().map(|&step| step as i32).sum::<i32>();
+    // a custom scale's "octave" is wherever its steps add up to
+    let octave = sum(steps);
+    let (num_octaves, index) = (
+        (degree - 1) as usize / steps.len(),
+        (degree - 1) as usize % steps.len(),
+    );
+    octave * num_octaves as i32 + sum(&steps[..index])
 }
 
 pub struct SongContext {
-    bpm: u16,
-    key: Key,
-    scale: Scale,
-    phase: f32,
-    harmony: Option<Harmony>,
+    // the main voice (top level instructions) is always the first one
+    voices: Vec<Voice>,
+    // the voice `pc` belongs to
+    current_voice: usize,
+    // how many samples of the mix have been handed out by iterate so far
+    num_samples_mixed: usize,
+    num_sounding_voices: usize,
+    master_gain: f32,
 
     skip_to_note_index: Option<usize>,
 
     pc: usize,
     instructions: Vec<Instruction>,
-    on_harmony: Option<usize>,
-    off_on_next_tick: Option<usize>,
     on_instructions: HashSet<usize>,
 }
 
 impl SongContext {
-    pub fn current_instruction(&self) -> &Instruction {
-        &self.instructions[self.pc]
+    // None once every instruction has been evaluated, there might still be
+    // some samples left to mix though
+    pub fn current_instruction(&self) -> Option<&Instruction> {
+        self.instructions.get(self.pc)
     }
 
     pub fn is_done(&self) -> bool {
-        self.pc >= self.instructions.len()
+        self.pc >= self.instructions.len() && !self.voices.iter().any(Voice::has_pending)
     }
 
     pub fn skip(&mut self) {
-        self.pc += 1;
+        let voice = &mut self.voices[self.current_voice];
+        // whatever the skipped note was tied to gets attacked from scratch
+        voice.tied_from_prev = false;
+        voice.advance();
+        self.schedule();
+    }
+
+    // like iterate, except notes that don't intersect `selection` are skipped
+    // over silently. everything else still gets evaluated so that the bpm, key
+    // and harmony are right by the time we get to the selection
+    pub fn iterate_selection(
+        &mut self,
+        spans: &[SpannedInstruction],
+        selection: &RangeInclusive<usize>,
+    ) -> Vec<f32> {
+        match self.current_instruction() {
+            Some(Instruction::PlayNote(_) | Instruction::PlayRest(_))
+                if !ranges_intersect(selection.clone(), spans[self.pc].range()) =>
+            {
+                self.skip();
+                vec![]
+            }
+            _ => self.iterate(),
+        }
+    }
+
+    // everything from the current instruction until the end of the song
+    pub fn render_to_end(&mut self) -> Vec<f32> {
+        let mut samples = vec![];
+        while !self.is_done() {
+            samples.extend(self.iterate());
+        }
+        samples
     }
 
+    // evaluates the current instruction and returns whatever part of the mix
+    // every voice has caught up to. voices take turns by whichever one is the
+    // furthest behind, so this can return nothing even after playing a note
     pub fn iterate(&mut self) -> Vec<f32> {
         if self.is_done() {
             panic!("iteration called on done song context")
         }
+        if self.pc >= self.instructions.len() {
+            return self.mix();
+        }
 
-        if let Some(i) = self.off_on_next_tick {
+        let pc = self.pc;
+        let silent = self.skip_to_note_index.is_some_and(|i| pc < i);
+        if let Instruction::SetGain(Channel::Master, db) = self.instructions[pc] {
+            self.master_gain = mixer::db_to_gain(db);
+        }
+        let voice = &mut self.voices[self.current_voice];
+        if let Some(i) = voice.off_on_next_tick.take() {
             self.on_instructions.remove(&i);
         }
+        voice.eval(&self.instructions, silent);
 
-        let cur_instruction = self.instructions[self.pc];
-        let samples = self.eval(cur_instruction).into_iter().flatten().collect();
-        match cur_instruction {
+        match self.instructions[pc] {
             Instruction::SetBPM(_)
             | Instruction::SetKey(_)
             | Instruction::SetScale(_)
             | Instruction::PlayNote(_)
-            | Instruction::SkipToNote => {
-                self.off_on_next_tick = Some(self.pc);
-                self.on_instructions.insert(self.pc);
+            | Instruction::SkipToNote
+            | Instruction::BeginVoice(_)
+            | Instruction::EndVoice
+            | Instruction::SetOctave(_)
+            | Instruction::SetVolume(_)
+            | Instruction::SetWaveform(..)
+            | Instruction::SetEnvelope(_)
+            | Instruction::SetGain(..)
+            | Instruction::SetTimeSignature(_)
+            | Instruction::BarLine
+            | Instruction::BeatBoundary
+            | Instruction::SetStrict(_)
+            | Instruction::BeginTuplet(_)
+            | Instruction::EndTuplet => {
+                voice.off_on_next_tick = Some(pc);
+                self.on_instructions.insert(pc);
+            }
+            Instruction::PlayRest(rest) => {
+                if rest.stops_harmony {
+                    if let Some(prev_harmony) = voice.on_harmony.take() {
+                        self.on_instructions.remove(&prev_harmony);
+                    }
+                }
+                voice.off_on_next_tick = Some(pc);
+                self.on_instructions.insert(pc);
             }
             Instruction::SetHarmony(_) => {
-                if let Some(prev_harmony) = self.on_harmony {
+                if let Some(prev_harmony) = voice.on_harmony {
                     self.on_instructions.remove(&prev_harmony);
                 }
-                self.on_instructions.insert(self.pc);
-                self.on_harmony = Some(self.pc);
+                self.on_instructions.insert(pc);
+                voice.on_harmony = Some(pc);
             }
         };
-        self.pc += 1;
+        voice.advance();
+        self.schedule();
+        self.mix()
+    }
+
+    // the next instruction comes from whichever voice has rendered the least
+    fn schedule(&mut self) {
+        let next = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter_map(|(i, voice)| Some((i, voice.next_instruction_index()?, voice)))
+            .min_by_key(|(_, _, voice)| voice.num_samples_rendered);
+        match next {
+            Some((i, pc, _)) => {
+                self.current_voice = i;
+                self.pc = pc;
+            }
+            None => self.pc = self.instructions.len(),
+        }
+    }
+
+    // mixes down everything up to where the voice that's furthest behind is,
+    // or all of it (releases and all) once there's nothing left to evaluate
+    fn mix(&mut self) -> Vec<f32> {
+        let mixed_up_to = self
+            .voices
+            .iter()
+            .filter(|voice| voice.next_instruction_index().is_some())
+            .map(|voice| voice.num_samples_rendered)
+            .min()
+            .unwrap_or_else(|| {
+                let ends = self.voices.iter().map(|voice| voice.end_of_pending());
+                ends.max().unwrap_or(0)
+            });
+        let num_samples = mixed_up_to.saturating_sub(self.num_samples_mixed);
+        let mut samples = vec![0.; num_samples];
+        let volume = 1. / self.num_sounding_voices.max(1) as f32;
+        for voice in &mut self.voices {
+            for (mixed, sample) in samples.iter_mut().zip(voice.drain_pending(num_samples)) {
+                *mixed += sample * volume;
+            }
+        }
+        for sample in &mut samples {
+            *sample = mixer::soft_limit(*sample * self.master_gain);
+        }
+        self.num_samples_mixed += num_samples;
         samples
     }
 
+    // in decibels, melody and harmony are set for every voice
+    pub fn set_gain(&mut self, channel: Channel, db: f32) {
+        match channel {
+            Channel::Master => self.master_gain = mixer::db_to_gain(db),
+            Channel::Melody | Channel::Harmony => {
+                for voice in &mut self.voices {
+                    voice.set_gain(channel, db);
+                }
+            }
+        }
+    }
+
+    pub fn set_bpm(&mut self, bpm: u16) {
+        for voice in &mut self.voices {
+            voice.set_bpm(bpm);
+        }
+    }
+
+    pub fn set_key(&mut self, key: Key) {
+        for voice in &mut self.voices {
+            voice.set_key(key);
+        }
+    }
+
+    // in samples a second, which has to be set before anything's rendered
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        for voice in &mut self.voices {
+            voice.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        // every voice has the same one
+        self.voices[0].sample_rate
+    }
+
     pub fn default(instructions: Vec<Instruction>) -> Self {
         let skip_to_index = find_skip_to_index(&instructions);
         Self::new(
@@ -492,153 +828,84 @@ impl SongContext {
         scale: Scale,
         skip_to_index: Option<usize>,
     ) -> Self {
-        Self {
-            bpm,
-            key,
-            scale,
-            phase: 0.,
-            harmony: None,
-            pc: 0,
-            instructions,
-            on_harmony: None,
-            off_on_next_tick: None,
-            on_instructions: HashSet::new(),
-            skip_to_note_index: skip_to_index,
-        }
-    }
-
-    fn chord_freqs(&self) -> Vec<f32> {
-        let mut freqs = vec![];
-        if let Some(mut harmony) = self.harmony {
-            let num_semitones_to_chord_base = scale_degree_to_semitones(self.scale, harmony.degree);
-            let freq_of_base_of_chord =
-                shift_up_by_interval(self.freq_of_tonic(), num_semitones_to_chord_base);
-            freqs.push(freq_of_base_of_chord);
-
-            let semitones_to_3 = scale_degree_to_semitones(harmony.scale, 3);
-            let freq_of_3 = shift_up_by_interval(freq_of_base_of_chord, semitones_to_3);
-            freqs.push(freq_of_3);
-
-            let semitones_to_5 = scale_degree_to_semitones(harmony.scale, 5);
-            let freq_of_5 = shift_up_by_interval(freq_of_base_of_chord, semitones_to_5);
-            freqs.push(freq_of_5);
-
-            if harmony.add_7 {
-                let semitones_to_7 = scale_degree_to_semitones(harmony.scale, 7);
-                let freq_of_7 = shift_up_by_interval(freq_of_base_of_chord, semitones_to_7);
-                freqs.push(freq_of_7);
-            }
-
-            while harmony.shift < 0 {
-                let last = freqs.pop().unwrap();
-                freqs.insert(0, last / 2.);
-                harmony.shift += 1;
-            }
-        }
-
-        freqs
-    }
-
-    fn render_note(&mut self, n: Note) -> impl Iterator<Item = f32> {
-        let freq = self.pitch_to_freq(n.pitch);
-        let quarter_duration = 60_000 / self.bpm as usize;
-        let duration_ms =
-            quarter_duration * n.duration.numerator as usize / n.duration.denominator as usize;
-
-        let mut freqs = self.chord_freqs();
-        freqs.push(freq);
-
-        let (samples, ending_phase) =
-            freqs_to_samples(duration_ms, freqs.into_iter(), 1., self.phase);
-        self.phase = ending_phase;
-        samples
-    }
-
-    fn pitch_to_freq(&mut self, pitch: NotePitch) -> f32 {
-        match pitch.enum_ {
-            dsl::NotePitchEnum::ScaleDegree(degree) => {
-                let offset = scale_degree_to_semitones(self.scale, degree);
-                let offset = match pitch.accidental {
-                    dsl::Accidental::Natural => offset,
-                    dsl::Accidental::Sharp => offset + 1,
-                    dsl::Accidental::Flat => offset - 1,
-                };
-                let mut freq = shift_up_by_interval(self.freq_of_tonic(), offset);
-
-                if pitch.octave > 0 {
-                    freq *= 2.0f32.powf(pitch.octave as f32);
-                } else if pitch.octave < 0 {
-                    freq /= 2.0f32.powf(-pitch.octave as f32);
+        let mut voices = vec![Voice::new(None, bpm, key, scale)];
+        // a voice starts out with whatever bpm, key and scale the top level has
+        // set by the time the voice first shows up in the song
+        let (mut bpm, mut key, mut scale) = (bpm, key, scale);
+        let mut current_voice = 0;
+        for (i, inst) in instructions.iter().enumerate() {
+            match *inst {
+                Instruction::BeginVoice(name) => {
+                    current_voice = match voices.iter().position(|v| v.name == Some(name)) {
+                        Some(voice) => voice,
+                        None => {
+                            voices.push(Voice::new(Some(name), bpm, key, scale));
+                            voices.len() - 1
+                        }
+                    };
                 }
-                freq
+                Instruction::SetBPM(new_bpm) if current_voice == 0 => bpm = new_bpm,
+                Instruction::SetKey(new_key) if current_voice == 0 => key = new_key,
+                Instruction::SetScale(new_scale) if current_voice == 0 => scale = new_scale,
+                _ => {}
             }
-        }
-    }
-
-    fn freq_of_tonic(&self) -> f32 {
-        to_freq(self.key.abc, self.key.accidental)
-    }
-
-    pub fn play<'a>(&'a mut self, instrs: &'a [Instruction]) -> impl Iterator<Item = f32> + 'a {
-        instrs
-            .iter()
-            .enumerate()
-            .flat_map(move |(_i, &inst)| self.eval(inst))
-            .flatten()
-    }
-
-    fn eval(&mut self, inst: Instruction) -> Option<impl Iterator<Item = f32>> {
-        match inst {
-            Instruction::SetBPM(bpm) => {
-                self.bpm = bpm;
-                None
-            }
-            Instruction::SetKey(key) => {
-                self.key = key;
-                None
-            }
-            Instruction::SetScale(scale) => {
-                self.scale = scale;
-                None
-            }
-            Instruction::PlayNote(note) => {
-                if let Some(skip_to_note_index) = self.skip_to_note_index {
-                    // this is a little bit strange because eval didn't know about pc and now it does...
-                    if self.pc < skip_to_note_index {
-                        return None;
-                    }
-                }
-                Some(self.render_note(note))
-            }
-            Instruction::SkipToNote => None,
-            Instruction::SetHarmony(harmony) => {
-                self.harmony = Some(harmony);
-                None
+            voices[current_voice].instructions.push(i);
+            if let Instruction::EndVoice = inst {
+                current_voice = 0;
             }
         }
+        let num_sounding_voices = voices
+            .iter()
+            .filter(|voice| {
+                voice.instructions.iter().any(|&i| {
+                    matches!(
+                        instructions[i],
+                        Instruction::PlayNote(_) | Instruction::PlayRest(_)
+                    )
+                })
+            })
+            .count();
+
+        let mut ctx = Self {
+            voices,
+            current_voice: 0,
+            num_samples_mixed: 0,
+            num_sounding_voices,
+            master_gain: 1.,
+            pc: 0,
+            instructions,
+            on_instructions: HashSet::new(),
+            skip_to_note_index: skip_to_index,
+        };
+        ctx.schedule();
+        ctx
     }
 }
 
 fn freq_to_abc(freq: f32) -> String {
     let a = 440.0;
     let n = (12.0 * (freq / a).log2()).round() as i32;
-    let abc = match n % 12 {
-        0 => "A",
-        1 => "A# / Bb",
-        2 => "B",
-        3 => "C",
-        4 => "C# / Db",
-        5 => "D",
-        6 => "D# / Eb",
-        7 => "E",
-        8 => "F",
-        9 => "F# / Gb",
-        10 => "G",
-        11 => "G# / Ab",
+    let (abc, flat) = match n.rem_euclid(12) {
+        0 => ("A", None),
+        1 => ("A#", Some("Bb")),
+        2 => ("B", None),
+        3 => ("C", None),
+        4 => ("C#", Some("Db")),
+        5 => ("D", None),
+        6 => ("D#", Some("Eb")),
+        7 => ("E", None),
+        8 => ("F", None),
+        9 => ("F#", Some("Gb")),
+        10 => ("G", None),
+        11 => ("G#", Some("Ab")),
         _ => panic!("impossible"),
     };
-    let octave = n / 12 - 1;
-    format!("{}{}", abc, octave)
+    // octaves start from C, and A4 is the 440
+    let octave = 4 + (n + 9).div_euclid(12);
+    match flat {
+        Some(flat) => format!("{abc}{octave} / {flat}{octave}"),
+        None => format!("{abc}{octave}"),
+    }
 }
 
 fn ranges_intersect<T: PartialOrd>(a: std::ops::RangeInclusive<T>, b: std::ops::Range<T>) -> bool {
@@ -0,0 +1,792 @@
+use std::io::{self, Write};
+
+use dsl::{Accidental, Duration, Instruction, Key, Scale, TimeSignature, ABC};
+
+use crate::check::Beats;
+use crate::{key_semitones, scale_degree_to_semitones, SongContext};
+
+const TICKS_PER_QUARTER: u16 = 480;
+
+/// Writes the song as a type 1 Standard MIDI File. The first track has the
+/// tempo, key signature and time signature changes, then every voice gets a melody track and a
+/// harmony track (if it has any notes to go on them). Notes are worked out the
+/// same way the synth works out their frequencies, ties come out as one long
+/// note, and a chord is held for as long as the synth would sustain it.
+pub fn write_midi(mut w: impl Write, instructions: &[Instruction]) -> io::Result<()> {
+    let mut ctx = SongContext::default(instructions.to_vec());
+    let mut conductor = Track::new("rejectsynth".to_string());
+    // when the main voice's tempo changes, and what to
+    let mut tempo_changes: Vec<(u64, u16)> = vec![];
+    let mut tracks = vec![];
+    let channels = (0..16).filter(|&channel| channel != 9).collect::<Vec<u8>>();
+
+    for (n, voice) in ctx.voices.iter_mut().enumerate() {
+        let is_main = n == 0;
+        let prefix = voice
+            .name
+            .map_or(String::new(), |name| format!("{} ", name.as_str()));
+        let mut melody = Track::new(format!("{prefix}melody"));
+        let mut harmony = Track::new(format!("{prefix}harmony"));
+        let melody_channel = channels[(2 * n) % channels.len()];
+        let harmony_channel = channels[(2 * n + 1) % channels.len()];
+
+        if is_main {
+            conductor.set(0, tempo(voice.bpm));
+            tempo_changes.push((0, voice.bpm));
+        }
+        let mut clock = Clock::new(voice.bpm);
+        let mut held_chord: Vec<u8> = vec![];
+        let mut tied_from_prev = false;
+        let release_chord = |harmony: &mut Track, held_chord: &mut Vec<u8>, now| {
+            for note in held_chord.drain(..) {
+                harmony.push(now, note_off(harmony_channel, note));
+            }
+        };
+        // strikes the voice's chord, unless it's already being held
+        let hold_chord =
+            |harmony: &mut Track, held_chord: &mut Vec<u8>, voice: &crate::Voice, now| {
+                let chord = voice
+                    .chord_pitches()
+                    .into_iter()
+                    .map(midi_note)
+                    .collect::<Vec<_>>();
+                if chord != *held_chord {
+                    release_chord(harmony, held_chord, now);
+                    let velocity = velocity(voice.levels().1);
+                    for &note in &chord {
+                        harmony.push(now, note_on(harmony_channel, note, velocity));
+                    }
+                    *held_chord = chord;
+                }
+            };
+
+        while let Some(i) = voice.next_instruction_index() {
+            let now = clock.now_us();
+            match instructions[i] {
+                Instruction::PlayNote(note) => {
+                    hold_chord(&mut harmony, &mut held_chord, voice, now);
+                    let pitch = midi_note(voice.note_pitch(note.pitch));
+                    if !tied_from_prev {
+                        let velocity = velocity(voice.levels().0);
+                        melody.push(now, note_on(melody_channel, pitch, velocity));
+                    }
+                    clock.advance(note.duration);
+                    tied_from_prev = voice.ties_into_next_note(note, instructions);
+                    if !tied_from_prev {
+                        melody.push(clock.now_us(), note_off(melody_channel, pitch));
+                    }
+                }
+                Instruction::PlayRest(rest) => {
+                    if rest.stops_harmony {
+                        release_chord(&mut harmony, &mut held_chord, now);
+                    } else {
+                        hold_chord(&mut harmony, &mut held_chord, voice, now);
+                    }
+                    clock.advance(rest.duration);
+                    tied_from_prev = false;
+                }
+                Instruction::SetHarmony(_) => release_chord(&mut harmony, &mut held_chord, now),
+                _ => {}
+            }
+            // the voice keeps track of the key, scale, harmony and so on for us
+            voice.eval(instructions, true);
+            voice.advance();
+            let now = clock.now_us();
+            if let Instruction::SetBPM(_) = instructions[i] {
+                clock.set_bpm(voice.bpm);
+            }
+            if is_main {
+                match instructions[i] {
+                    Instruction::SetBPM(_) => {
+                        conductor.set(now, tempo(voice.bpm));
+                        match tempo_changes.last_mut() {
+                            Some(last) if last.0 == now => last.1 = voice.bpm,
+                            _ => tempo_changes.push((now, voice.bpm)),
+                        }
+                    }
+                    Instruction::SetKey(_) | Instruction::SetScale(_) => {
+                        conductor.set(now, key_signature(voice.key, voice.scale))
+                    }
+                    Instruction::SetTimeSignature(time) => conductor.set(now, time_signature(time)),
+                    _ => {}
+                }
+            }
+        }
+        release_chord(&mut harmony, &mut held_chord, clock.now_us());
+
+        tracks.extend(
+            [melody, harmony]
+                .into_iter()
+                .filter(|track| !track.events.is_empty()),
+        );
+    }
+
+    // everything's timed in microseconds so far, because voices can have their
+    // own tempo. the tempo changes are all on the main voice's timeline though
+    let tempo_map = TempoMap::new(&tempo_changes);
+
+    w.write_all(b"MThd")?;
+    w.write_all(&6u32.to_be_bytes())?;
+    w.write_all(&1u16.to_be_bytes())?;
+    w.write_all(&(tracks.len() as u16 + 1).to_be_bytes())?;
+    w.write_all(&TICKS_PER_QUARTER.to_be_bytes())?;
+    for track in [conductor].iter().chain(&tracks) {
+        track.write(&mut w, &tempo_map)?;
+    }
+    w.flush()
+}
+
+struct Track {
+    name: String,
+    // microseconds into the song, and the event itself. always in order
+    events: Vec<(u64, Vec<u8>)>,
+}
+
+impl Track {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            events: vec![],
+        }
+    }
+
+    fn push(&mut self, time_us: u64, event: Vec<u8>) {
+        self.events.push((time_us, event));
+    }
+
+    // a tempo or key signature straight after another one replaces it
+    fn set(&mut self, time_us: u64, event: Vec<u8>) {
+        match self.events.last_mut() {
+            Some((last_us, last)) if *last_us == time_us && last[..2] == event[..2] => {
+                *last = event
+            }
+            _ => self.push(time_us, event),
+        }
+    }
+
+    fn write(&self, w: &mut impl Write, tempo_map: &TempoMap) -> io::Result<()> {
+        let mut data = vec![];
+        let name = self.name.as_bytes();
+        data.push(0);
+        data.extend([0xff, 0x03]);
+        write_vlq(&mut data, name.len() as u32);
+        data.extend(name);
+
+        let mut prev_ticks = 0;
+        for (time_us, event) in &self.events {
+            let ticks = tempo_map.ticks(*time_us);
+            write_vlq(&mut data, (ticks - prev_ticks) as u32);
+            data.extend(event);
+            prev_ticks = ticks;
+        }
+        data.extend([0, 0xff, 0x2f, 0]);
+
+        w.write_all(b"MTrk")?;
+        w.write_all(&(data.len() as u32).to_be_bytes())?;
+        w.write_all(&data)
+    }
+}
+
+// where a voice is up to, as exactly how many beats it's been since its tempo
+// last changed, the same way the synth counts samples. it only gets rounded to
+// a microsecond when something asks what time it is, so the rounding can't
+// add up from one note to the next
+struct Clock {
+    bpm: u16,
+    bpm_start_us: u64,
+    beats_since_bpm: Beats,
+}
+
+impl Clock {
+    fn new(bpm: u16) -> Self {
+        Self {
+            bpm,
+            bpm_start_us: 0,
+            beats_since_bpm: Beats::ZERO,
+        }
+    }
+
+    fn now_us(&self) -> u64 {
+        let beats = &self.beats_since_bpm;
+        let denominator = beats.denominator() as u128 * self.bpm as u128;
+        let us = (beats.numerator() as u128 * 60_000_000 + denominator / 2) / denominator;
+        self.bpm_start_us + us as u64
+    }
+
+    fn advance(&mut self, duration: Duration) {
+        self.beats_since_bpm += duration;
+    }
+
+    fn set_bpm(&mut self, bpm: u16) {
+        self.bpm_start_us = self.now_us();
+        self.beats_since_bpm = Beats::ZERO;
+        self.bpm = bpm;
+    }
+}
+
+// where each tempo change happens, in microseconds and in ticks, along with
+// the tempo from then on. the bpm rather than the microseconds a quarter note
+// lasts in the file, which get rounded, so the main voice's notes land
+// exactly on the ticks their beats are on
+struct TempoMap(Vec<(u64, u64, u16)>);
+
+impl TempoMap {
+    fn new(tempo_changes: &[(u64, u16)]) -> Self {
+        let mut changes: Vec<(u64, u64, u16)> = vec![];
+        for &(time_us, bpm) in tempo_changes {
+            let ticks = match changes.last() {
+                Some(&(prev_us, prev_ticks, prev_bpm)) => {
+                    prev_ticks + Self::ticks_at(time_us - prev_us, prev_bpm)
+                }
+                None => 0,
+            };
+            changes.push((time_us, ticks, bpm));
+        }
+        Self(changes)
+    }
+
+    fn ticks(&self, time_us: u64) -> u64 {
+        let i = self
+            .0
+            .partition_point(|&(change_us, _, _)| change_us <= time_us);
+        let (change_us, change_ticks, bpm) = self.0[i.saturating_sub(1)];
+        change_ticks + Self::ticks_at(time_us - change_us, bpm)
+    }
+
+    // how many ticks `us` microseconds is at `bpm`, to the nearest one
+    fn ticks_at(us: u64, bpm: u16) -> u64 {
+        let ticks_per_minute = TICKS_PER_QUARTER as u64 * bpm as u64;
+        (us * ticks_per_minute + 30_000_000) / 60_000_000
+    }
+}
+
+// semitones from A4, which is MIDI note 69
+fn midi_note(pitch: i32) -> u8 {
+    (69 + pitch).clamp(0, 127) as u8
+}
+
+fn velocity(level: f32) -> u8 {
+    (level * 100.).round().clamp(1., 127.) as u8
+}
+
+fn note_on(channel: u8, note: u8, velocity: u8) -> Vec<u8> {
+    vec![0x90 | channel, note, velocity]
+}
+
+fn note_off(channel: u8, note: u8) -> Vec<u8> {
+    vec![0x80 | channel, note, 0x40]
+}
+
+fn tempo(bpm: u16) -> Vec<u8> {
+    let us_per_quarter = (60_000_000 / bpm as u32).to_be_bytes();
+    vec![
+        0xff,
+        0x51,
+        3,
+        us_per_quarter[1],
+        us_per_quarter[2],
+        us_per_quarter[3],
+    ]
+}
+
+fn key_signature(key: Key, scale: Scale) -> Vec<u8> {
+    let (mut sharps, is_minor) = sharps(key, scale);
+    // spell it the other way round when there'd be more than 7 accidentals
+    if sharps > 7 {
+        sharps -= 12;
+    } else if sharps < -7 {
+        sharps += 12;
+    }
+    vec![0xff, 0x59, 2, sharps as u8, is_minor as u8]
+}
+
+fn time_signature(time: TimeSignature) -> Vec<u8> {
+    let denominator = time.beat_unit.trailing_zeros() as u8;
+    // a metronome click every quarter note, and 8 32nd notes to a quarter note
+    vec![0xff, 0x58, 4, time.beats, denominator, 24, 8]
+}
+
+// how many sharps (or flats, when it's negative) the key signature has, which
+// can be more than 7 for keys nobody would write like G#. MIDI only knows
+// about major and minor keys, so the modes get the signature of the major
+// scale they come from, and the ones that sound minor are marked minor.
+// pentatonics, blues and custom scales get whichever's closest
+fn sharps(key: Key, scale: Scale) -> (i8, bool) {
+    let fifths = match key.abc {
+        ABC::F => -1,
+        ABC::C => 0,
+        ABC::G => 1,
+        ABC::D => 2,
+        ABC::A => 3,
+        ABC::E => 4,
+        ABC::B => 5,
+    };
+    let fifths = fifths
+        + match key.accidental {
+            Accidental::Natural => 0,
+            Accidental::Sharp => 7,
+            Accidental::Flat => -7,
+        };
+    let (shift, is_minor) = match scale {
+        Scale::Major | Scale::MajorPentatonic | Scale::Custom(_) => (0, false),
+        Scale::Lydian => (1, false),
+        Scale::Mixolydian => (-1, false),
+        Scale::Dorian => (-2, true),
+        Scale::Minor
+        | Scale::HarmonicMinor
+        | Scale::MelodicMinor
+        | Scale::MinorPentatonic
+        | Scale::Blues => (-3, true),
+        Scale::Phrygian => (-4, true),
+        Scale::Locrian => (-5, true),
+    };
+    (fifths + shift, is_minor)
+}
+
+fn write_vlq(data: &mut Vec<u8>, mut n: u32) {
+    let mut bytes = vec![(n & 0x7f) as u8];
+    n >>= 7;
+    while n > 0 {
+        bytes.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    data.extend(bytes.iter().rev());
+}
+
+/// Turns the first track of a Standard MIDI File that has any notes on it into
+/// .rej source. Only one note plays at a time, so chords come out as their top
+/// note. Everything gets quantised to 16th notes, and lengths that don't have a
+/// duration of their own are tied together out of ones that do. The key and
+/// scale are worked out from the notes if they're not given, or taken from the
+/// file's key signature if it has one.
+pub fn import_midi(bytes: &[u8], key: Option<Key>, scale: Option<Scale>) -> io::Result<String> {
+    let file = MidiFile::parse(bytes)?;
+    let Some(notes) = file.tracks.iter().find(|notes| !notes.is_empty()) else {
+        return Err(invalid("there aren't any notes in this file"));
+    };
+    let melody = quantise(top_line(notes), file.ticks_per_quarter);
+
+    let (key, scale) = match (key, scale, file.key_signature) {
+        (None, None, Some((sharps, is_minor))) => key_from_signature(sharps, is_minor),
+        (key, scale, _) => detect_key(&melody, key, scale),
+    };
+    let prefer_flats = sharps(key, scale).0 < 0;
+
+    // put the voice's octave wherever most of the notes are, so there are as
+    // few +s and -s as possible
+    let tonic = key_semitones(key);
+    let mut octaves = melody
+        .iter()
+        .map(|note| (note.note as i32 - 69 - tonic).div_euclid(12))
+        .collect::<Vec<_>>();
+    octaves.sort();
+    let octave = octaves.get(octaves.len() / 2).copied().unwrap_or(0);
+
+    let bpm = file
+        .us_per_quarter
+        .map_or(120, |us| (60_000_000. / us as f64).round() as u32);
+    let mut song = format!("bpm {bpm} key {key} scale {scale}");
+    let time = file
+        .time_signature
+        .and_then(|(numerator, denominator)| {
+            TimeSignature::new(numerator, 1u8.checked_shl(denominator as u32)?)
+        })
+        .filter(|&time| time != TimeSignature::COMMON);
+    if let Some(TimeSignature { beats, beat_unit }) = time {
+        song += &format!(" time {beats}/{beat_unit}");
+    }
+    song += "\n";
+    if octave != 0 {
+        song += &format!("octave {octave}\n");
+    }
+
+    // every note and rest, and when it ends
+    let mut words = vec![];
+    let mut now = 0;
+    for note in &melody {
+        for (before, after, dot) in split_duration(note.start - now) {
+            words.push((format!("{before}r{after}{dot}"), note.start));
+        }
+        let rel = note.note as i32 - 69 - tonic - 12 * octave;
+        let pitch = spell_pitch(rel, scale, prefer_flats)
+            .ok_or_else(|| invalid(format!("can't fit MIDI note {} into the scale", note.note)))?;
+        let pieces = split_duration(note.end - note.start).collect::<Vec<_>>();
+        let num_pieces = pieces.len();
+        for (i, (before, after, dot)) in pieces.into_iter().enumerate() {
+            let tie_from = if i > 0 { "_" } else { "" };
+            let tie_to = if i + 1 < num_pieces { "_" } else { "" };
+            words.push((
+                format!("{tie_from}{before}{pitch}{after}{dot}{tie_to}"),
+                note.end,
+            ));
+        }
+        now = note.end;
+    }
+
+    // a line per bar, or per however many bars a long note goes on for
+    let bar = file.time_signature.map_or(16, |(numerator, denominator)| {
+        ((numerator as u64 * 16) >> denominator).max(1)
+    });
+    let mut line_start = 0;
+    let mut line = vec![];
+    for (word, end) in words {
+        line.push(word);
+        if end / bar > line_start / bar {
+            song += &line.join(" ");
+            song += "\n";
+            line.clear();
+            line_start = end;
+        }
+    }
+    if !line.is_empty() {
+        song += &line.join(" ");
+        song += "\n";
+    }
+    Ok(song)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[derive(Clone, Copy)]
+struct MidiNote {
+    // in ticks, until it's been quantised, then in 16th notes
+    start: u64,
+    end: u64,
+    note: u8,
+}
+
+struct MidiFile {
+    ticks_per_quarter: u16,
+    // the first of each of these, songs that change them halfway through
+    // don't come out right
+    us_per_quarter: Option<u32>,
+    key_signature: Option<(i8, bool)>,
+    // the numerator, and the denominator as a power of 2
+    time_signature: Option<(u8, u8)>,
+    tracks: Vec<Vec<MidiNote>>,
+}
+
+impl MidiFile {
+    fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let mut r = Reader(bytes);
+        if r.take(4)? != b"MThd" {
+            return Err(invalid("not a MIDI file"));
+        }
+        let header_len = r.u32()? as usize;
+        let mut header = Reader(r.take(header_len)?);
+        let _format = header.u16()?;
+        let num_tracks = header.u16()?;
+        let ticks_per_quarter = header.u16()?;
+        if ticks_per_quarter & 0x8000 != 0 || ticks_per_quarter == 0 {
+            return Err(invalid("MIDI files timed in SMPTE frames aren't supported"));
+        }
+
+        let mut file = Self {
+            ticks_per_quarter,
+            us_per_quarter: None,
+            key_signature: None,
+            time_signature: None,
+            tracks: vec![],
+        };
+        for _ in 0..num_tracks {
+            let kind = r.take(4)?;
+            let len = r.u32()? as usize;
+            let chunk = r.take(len)?;
+            // anything that isn't a track is allowed, and gets skipped
+            if kind == b"MTrk" {
+                let notes = file.parse_track(Reader(chunk))?;
+                file.tracks.push(notes);
+            }
+        }
+        Ok(file)
+    }
+
+    fn parse_track(&mut self, mut r: Reader) -> io::Result<Vec<MidiNote>> {
+        let mut notes = vec![];
+        // channel, note and when it started, for every note that's on
+        let mut held: Vec<(u8, u8, u64)> = vec![];
+        let mut now = 0;
+        let mut running_status = 0;
+        while !r.0.is_empty() {
+            now += r.vlq()? as u64;
+            let byte = r.u8()?;
+            // channel events can leave the status out when it's the same as
+            // the last one's, so this might be the first data byte instead
+            let (status, first) = if byte & 0x80 == 0 {
+                (running_status, Some(byte))
+            } else {
+                // meta and sysex events cancel it, so there's nothing to run
+                // on from after one of those
+                running_status = if byte < 0xf0 { byte } else { 0 };
+                (byte, None)
+            };
+            match status {
+                0xff => {
+                    let kind = r.u8()?;
+                    let len = r.vlq()? as usize;
+                    let data = r.take(len)?;
+                    match (kind, data) {
+                        (0x51, &[a, b, c]) => {
+                            self.us_per_quarter
+                                .get_or_insert(u32::from_be_bytes([0, a, b, c]));
+                        }
+                        (0x58, &[numerator, denominator, ..]) => {
+                            self.time_signature.get_or_insert((numerator, denominator));
+                        }
+                        (0x59, &[sharps, is_minor]) => {
+                            self.key_signature
+                                .get_or_insert((sharps as i8, is_minor == 1));
+                        }
+                        _ => {}
+                    }
+                }
+                0xf0 | 0xf7 => {
+                    let len = r.vlq()? as usize;
+                    r.take(len)?;
+                }
+                0x80..=0xef => {
+                    let first = match first {
+                        Some(first) => first,
+                        None => r.u8()?,
+                    };
+                    let channel = status & 0x0f;
+                    match status & 0xf0 {
+                        0x80 | 0x90 => {
+                            let velocity = r.u8()?;
+                            let note = first;
+                            if let Some(i) =
+                                held.iter().position(|&(c, n, _)| c == channel && n == note)
+                            {
+                                let (_, _, start) = held.remove(i);
+                                notes.push(MidiNote {
+                                    start,
+                                    end: now,
+                                    note,
+                                });
+                            }
+                            if status & 0xf0 == 0x90 && velocity > 0 {
+                                held.push((channel, note, now));
+                            }
+                        }
+                        0xa0 | 0xb0 | 0xe0 => {
+                            r.u8()?;
+                        }
+                        _ => {}
+                    }
+                }
+                0 => return Err(invalid("a MIDI event is missing its status byte")),
+                _ => return Err(invalid(format!("unexpected MIDI event {status:#x}"))),
+            }
+        }
+        for (_, note, start) in held {
+            notes.push(MidiNote {
+                start,
+                end: now,
+                note,
+            });
+        }
+        notes.sort_by_key(|note| note.start);
+        Ok(notes)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if n > self.0.len() {
+            return Err(invalid("the MIDI file ends too soon"));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn vlq(&mut self) -> io::Result<u32> {
+        let mut n = 0;
+        loop {
+            let byte = self.u8()?;
+            n = (n << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+// the highest note wins when notes start together, and a note gets cut off
+// when the next one starts
+fn top_line(notes: &[MidiNote]) -> Vec<MidiNote> {
+    let mut line: Vec<MidiNote> = vec![];
+    for &note in notes {
+        match line.last_mut() {
+            Some(last) if last.start == note.start => {
+                if note.note > last.note {
+                    *last = note;
+                }
+            }
+            Some(last) => {
+                last.end = last.end.min(note.start);
+                line.push(note);
+            }
+            None => line.push(note),
+        }
+    }
+    line
+}
+
+// into 16th notes, never letting a note overlap the one before it or come
+// out with no length at all
+fn quantise(notes: Vec<MidiNote>, ticks_per_quarter: u16) -> Vec<MidiNote> {
+    let ticks_per_quarter = ticks_per_quarter as u64;
+    let to_16ths = |ticks: u64| (ticks * 4 + ticks_per_quarter / 2) / ticks_per_quarter;
+    let mut now = 0;
+    notes
+        .into_iter()
+        .map(|note| {
+            let start = to_16ths(note.start).max(now);
+            let end = to_16ths(note.end).max(start + 1);
+            now = end;
+            MidiNote {
+                start,
+                end,
+                note: note.note,
+            }
+        })
+        .collect()
+}
+
+// how many 16th notes each duration lasts, and how it's written: ~s before,
+// ~s after and the dot. longest first
+const DURATIONS: [(u64, &str, &str, &str); 10] = [
+    (32, "", "~~~~", ""),
+    (24, "", "~~", "."),
+    (16, "", "~~", ""),
+    (12, "", "~", "."),
+    (8, "", "~", ""),
+    (6, "", "", "."),
+    (4, "", "", ""),
+    (3, "~", "", "."),
+    (2, "~", "", ""),
+    (1, "~~", "", ""),
+];
+
+fn split_duration(
+    mut sixteenths: u64,
+) -> impl Iterator<Item = (&'static str, &'static str, &'static str)> {
+    std::iter::from_fn(move || {
+        let &(len, before, after, dot) = DURATIONS.iter().find(|(len, ..)| *len <= sixteenths)?;
+        sixteenths -= len;
+        Some((before, after, dot))
+    })
+}
+
+// the scale degree (with any octave markers and accidental) that's this many
+// semitones above the tonic
+fn spell_pitch(semitones: i32, scale: Scale, prefer_flats: bool) -> Option<String> {
+    let accidentals = if prefer_flats {
+        [("", 0), ("b", -1), ("#", 1)]
+    } else {
+        [("", 0), ("#", 1), ("b", -1)]
+    };
+    let len = crate::scale_ascending(&scale).len() as u8;
+    for (accidental, shift) in accidentals {
+        for degree in 1..=len {
+            let offset = scale_degree_to_semitones(scale, degree) + shift;
+            if (semitones - offset).rem_euclid(12) == 0 {
+                let octave = (semitones - offset) / 12;
+                let markers =
+                    if octave < 0 { "-" } else { "+" }.repeat(octave.unsigned_abs() as usize);
+                return Some(format!("{markers}{degree}{accidental}"));
+            }
+        }
+    }
+    None
+}
+
+fn key_from_signature(sharps: i8, is_minor: bool) -> (Key, Scale) {
+    // C is 3 semitones above A, every sharp is a fifth up and the relative
+    // minor is 3 semitones down from the major
+    let tonic = 3 + 7 * sharps as i32 - if is_minor { 3 } else { 0 };
+    let scale = if is_minor { Scale::Minor } else { Scale::Major };
+    (key_named(tonic, sharps < 0), scale)
+}
+
+// scores every key by how many of the notes (weighted by how long they are)
+// are in the scale, with the tonic counting double
+fn detect_key(notes: &[MidiNote], key: Option<Key>, scale: Option<Scale>) -> (Key, Scale) {
+    let tonics = key.map_or((0..12).collect(), |key| vec![key_semitones(key)]);
+    let scales = scale.map_or(vec![Scale::Major, Scale::Minor], |scale| vec![scale]);
+    let mut best = (0, tonics[0], scales[0]);
+    for &scale in &scales {
+        let len = crate::scale_ascending(&scale).len() as u8;
+        let in_scale = (1..=len)
+            .map(|degree| scale_degree_to_semitones(scale, degree) % 12)
+            .collect::<Vec<_>>();
+        for &tonic in &tonics {
+            let score = notes
+                .iter()
+                .map(|note| {
+                    let offset = (note.note as i32 - 69 - tonic).rem_euclid(12);
+                    let weight = if offset == 0 { 2 } else { 1 };
+                    weight * (note.end - note.start) * in_scale.contains(&offset) as u64
+                })
+                .sum::<u64>();
+            if score > best.0 {
+                best = (score, tonic, scale);
+            }
+        }
+    }
+    let (_, tonic, scale) = best;
+    let key = key.unwrap_or_else(|| {
+        // whichever way of spelling it has the fewest sharps or flats
+        let sharp = key_named(tonic, false);
+        let flat = key_named(tonic, true);
+        if sharps(flat, scale).0.abs() < sharps(sharp, scale).0.abs() {
+            flat
+        } else {
+            sharp
+        }
+    });
+    (key, scale)
+}
+
+// semitones above A, in any octave
+fn key_named(semitones: i32, flat: bool) -> Key {
+    use Accidental::*;
+    use ABC::*;
+    let (abc, accidental) = match (semitones.rem_euclid(12), flat) {
+        (0, _) => (A, Natural),
+        (1, false) => (A, Sharp),
+        (1, true) => (B, Flat),
+        (2, _) => (B, Natural),
+        (3, _) => (C, Natural),
+        (4, false) => (C, Sharp),
+        (4, true) => (D, Flat),
+        (5, _) => (D, Natural),
+        (6, false) => (D, Sharp),
+        (6, true) => (E, Flat),
+        (7, _) => (E, Natural),
+        (8, _) => (F, Natural),
+        (9, false) => (F, Sharp),
+        (9, true) => (G, Flat),
+        (10, _) => (G, Natural),
+        (_, false) => (G, Sharp),
+        (_, true) => (A, Flat),
+    };
+    Key { abc, accidental }
+}
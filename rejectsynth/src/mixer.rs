@@ -0,0 +1,20 @@
+// anything quieter than this goes through the limiter untouched
+const LIMITER_THRESHOLD: f32 = 0.8;
+
+pub(crate) fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+/// Squashes anything over the threshold smoothly towards 1 (or -1) instead of
+/// letting it clip. It's linear right up to the threshold and the curve above
+/// it starts off at the same slope, so there's no kink where it kicks in.
+pub(crate) fn soft_limit(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= LIMITER_THRESHOLD {
+        return sample;
+    }
+    let headroom = 1. - LIMITER_THRESHOLD;
+    let limited =
+        LIMITER_THRESHOLD + headroom * ((magnitude - LIMITER_THRESHOLD) / headroom).tanh();
+    limited.copysign(sample)
+}
@@ -0,0 +1,60 @@
+use dsl::Waveform;
+
+/// One sample of `waveform` at `t`, how far through the cycle we are (from 0
+/// to 1), where `dt` is how far it moves every sample. Everything with a hard
+/// edge gets smoothed out over the samples around it (PolyBLEP, or PolyBLAMP
+/// for the triangle's corners) so high notes don't alias.
+pub(crate) fn sample(waveform: Waveform, t: f32, dt: f32, noise: &mut u32) -> f32 {
+    match waveform {
+        Waveform::Sine => (t * std::f32::consts::TAU).sin(),
+        Waveform::Saw => 2. * t - 1. - poly_blep(t, dt),
+        Waveform::Square => pulse(t, dt, 0.5),
+        Waveform::Triangle => {
+            let naive = 4. * (t - 0.5).abs() - 1.;
+            naive + 4. * dt * (poly_blamp((t + 0.5) % 1., dt) - poly_blamp(t, dt))
+        }
+        Waveform::Pulse(width) => pulse(t, dt, width),
+        Waveform::Noise => {
+            // xorshift, it doesn't need to be any good, just cheap
+            *noise ^= *noise << 13;
+            *noise ^= *noise >> 17;
+            *noise ^= *noise << 5;
+            *noise as f32 / u32::MAX as f32 * 2. - 1.
+        }
+    }
+}
+
+fn pulse(t: f32, dt: f32, width: f32) -> f32 {
+    let naive = if t < width { 1. } else { -1. };
+    let sample = naive + poly_blep(t, dt) - poly_blep((t - width + 1.) % 1., dt);
+    // anything but a square wave is off center, so move it back and shrink it
+    // to fit between -1 and 1 again
+    let offset = 2. * width - 1.;
+    (sample - offset) / (1. + offset.abs())
+}
+
+// smooths out a jump from -1 to 1 at t = 0
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.
+    } else if t > 1. - dt {
+        let t = (t - 1.) / dt;
+        t * t + t + t + 1.
+    } else {
+        0.
+    }
+}
+
+// smooths out a corner at t = 0, the integral of poly_blep
+fn poly_blamp(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt - 1.;
+        -t * t * t / 3.
+    } else if t > 1. - dt {
+        let t = (t - 1.) / dt + 1.;
+        t * t * t / 3.
+    } else {
+        0.
+    }
+}
@@ -13,17 +13,109 @@ impl SpannedInstruction {
     }
 }
 
+// ~s before a note shorten it, ~s after lengthen it and a . dots it
+fn duration(num_half: u8, num_twice: u8, is_dotted: bool) -> dsl::Duration {
+    let mut numerator = if num_twice == 0 { 1 } else { num_twice * 2 };
+    let mut denominator = if num_half == 0 { 1 } else { num_half * 2 };
+    if is_dotted {
+        numerator *= 3;
+        denominator *= 2;
+    }
+    dsl::Duration::new(numerator, denominator)
+}
+
 peg::parser! {
     pub grammar grammar() for str {
         pub rule song() -> Vec<SpannedInstruction>
-            // TODO: will handle commas for reals at some point ithink
-            // = instr:spanned_instruction() ** _ _? { instr }
-            = instr:spanned_instruction() ** comma_or_space() _? { instr }
+            = _? items:separated(<song_item()>) _? { items }
+
+        rule song_item() -> Vec<SpannedInstruction>
+            = voice()
+            / voice_item()
+
+        rule voice_item() -> Vec<SpannedInstruction>
+            = tuplet()
+            / instr:spanned_instruction() { vec![instr] }
+
+        // `3:( ~1 ~2 ~3 )` or `5:4( ... )`. the notes inside come out already
+        // scaled, between BeginTuplet and EndTuplet like a voice's are
+        rule tuplet() -> Vec<SpannedInstruction>
+            = l:position!() tuplet:tuplet_ratio() "(" r:position!() _?
+              body:separated(<voice_item()>) _?
+              end_l:position!() ")" end_r:position!() {?
+                let mut instrs = vec![SpannedInstruction { instruction: Instruction::BeginTuplet(tuplet), l, r }];
+                for mut instr in body {
+                    match &mut instr.instruction {
+                        Instruction::PlayNote(dsl::Note { duration, .. })
+                        | Instruction::PlayRest(dsl::Rest { duration, .. }) => {
+                            *duration = tuplet.scale(*duration).ok_or("shorter notes in the tuplet")?;
+                        }
+                        _ => {}
+                    }
+                    instrs.push(instr);
+                }
+                instrs.push(SpannedInstruction { instruction: Instruction::EndTuplet, l: end_l, r: end_r });
+                Ok(instrs)
+            }
+
+        rule tuplet_ratio() -> dsl::Tuplet
+            = notes:uint() ":" in_time_of:uint()? {?
+                let notes = u8::try_from(notes).or(Err("tuplet like 3:( ... ) or 5:4( ... )"))?;
+                let in_time_of = in_time_of.map(u8::try_from).transpose().or(Err("tuplet like 5:4( ... )"))?;
+                dsl::Tuplet::new(notes, in_time_of).ok_or("tuplet like 3:( ... ) or 5:4( ... )")
+            }
+
+        // items with spaces or commas between them, and the commas come out as
+        // BeatBoundary instructions
+        rule separated(item: rule<Vec<SpannedInstruction>>) -> Vec<SpannedInstruction>
+            = first:item() rest:(comma:comma_or_space() next:item() { (comma, next) })* {
+                let mut instrs = first;
+                for (comma, next) in rest {
+                    instrs.extend(comma);
+                    instrs.extend(next);
+                }
+                instrs
+            }
+            / { vec![] }
 
-        // TODO: get rid of this, commas are gonna have real meaning
-        rule comma_or_space()
-            = _? "," _?
-            / _
+        // everything in the braces plays on the voice's own timeline, starting
+        // from the beginning of the song. the braces become BeginVoice and
+        // EndVoice instructions around whatever's inside
+        rule voice() -> Vec<SpannedInstruction>
+            = l:position!() "voice" _ name:voice_name() _? "{" r:position!() _?
+              body:separated(<voice_item()>) _?
+              end_l:position!() "}" end_r:position!() {
+                let mut instrs = vec![SpannedInstruction { instruction: Instruction::BeginVoice(name), l, r }];
+                instrs.extend(body);
+                instrs.push(SpannedInstruction { instruction: Instruction::EndVoice, l: end_l, r: end_r });
+                instrs
+            }
+
+        rule voice_name() -> dsl::VoiceName
+            = quiet!{ name:$(['a'..='z' | 'A'..='Z' | '_'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*) {?
+                dsl::VoiceName::new(name).ok_or("voice name")
+            } }
+            / expected!("voice name")
+
+        // comments are whitespace as far as the song is concerned, this digs
+        // them back out for highlighting and formatting
+        pub rule comments() -> Vec<std::ops::Range<usize>>
+            = comments:(c:comment_span() { Some(c) } / [_] { None })* {
+                comments.into_iter().flatten().collect()
+            }
+
+        rule comment_span() -> std::ops::Range<usize>
+            = l:position!() comment() r:position!() { l..r }
+
+        rule comment()
+            = "//" (!newline() [_])*
+            / "/*" (!"*/" [_])* "*/"
+
+        rule comma_or_space() -> Option<SpannedInstruction>
+            = _? l:position!() "," r:position!() _? {
+                Some(SpannedInstruction { instruction: Instruction::BeatBoundary, l, r })
+            }
+            / _ { None }
 
         pub rule spanned_instruction() -> SpannedInstruction
             = l:position!() instruction:instruction() r:position!() {
@@ -35,8 +127,9 @@ peg::parser! {
             }
 
         pub rule instruction() -> Instruction
-            = set_bpm() / set_key() / set_scale() / set_harmony() / play_note()
-            / skip_to_note()
+            = set_bpm() / set_key() / set_scale() / set_time_signature() / set_strict() / set_octave() / set_volume()
+            / set_waveform() / set_envelope() / set_gain() / set_harmony() / play_note() / play_rest()
+            / skip_to_note() / bar_line()
 
         rule skip_to_note() -> Instruction
             = ">" { Instruction::SkipToNote }
@@ -44,6 +137,85 @@ peg::parser! {
         rule set_bpm() -> Instruction
             = "bpm" _ bpm:uint() { Instruction::SetBPM(bpm as _) }
 
+        rule set_time_signature() -> Instruction
+            = "time" _ time:time_signature() { Instruction::SetTimeSignature(time) }
+
+        rule set_strict() -> Instruction
+            = "strict" group:(_ time:time_signature() { time })? { Instruction::SetStrict(group) }
+
+        rule time_signature() -> dsl::TimeSignature
+            = beats:uint() "/" beat_unit:uint() {?
+                let time = match (u8::try_from(beats), u8::try_from(beat_unit)) {
+                    (Ok(beats), Ok(beat_unit)) => dsl::TimeSignature::new(beats, beat_unit),
+                    _ => None,
+                };
+                time.ok_or("time signature like 3/4 or 6/8")
+            }
+
+        rule bar_line() -> Instruction
+            = "|" { Instruction::BarLine }
+
+        // moves every note after it in the voice up or down this many octaves
+        rule set_octave() -> Instruction
+            = "octave" _ octave:$(['+' | '-']? ['0'..='9']+) {?
+                octave.parse().map(Instruction::SetOctave).or(Err("octave"))
+            }
+
+        rule set_volume() -> Instruction
+            = "volume" _ volume:ufloat() { Instruction::SetVolume(volume) }
+
+        // `wave saw` changes the whole voice, `wave melody saw` or `wave harmony
+        // saw` just the one part. instrument is the same thing by another name
+        rule set_waveform() -> Instruction
+            = ("wave" / "instrument") _ part:(part:wave_part() _ { part })? waveform:waveform() {
+                Instruction::SetWaveform(part.unwrap_or(dsl::WavePart::All), waveform)
+            }
+
+        // attack, decay, sustain and release, e.g. `env 5 100 0.7 300 exp`
+        rule set_envelope() -> Instruction
+            = "env" nbspace() attack_ms:ms() nbspace() decay_ms:ms() nbspace() sustain:sustain()
+              nbspace() release_ms:ms() curve:(nbspace() curve:curve() { curve })? {
+                Instruction::SetEnvelope(dsl::Envelope {
+                    attack_ms,
+                    decay_ms,
+                    sustain,
+                    release_ms,
+                    curve: curve.unwrap_or(dsl::Curve::Linear),
+                })
+            }
+
+        // in decibels, e.g. `gain melody +3` or `gain harmony -6`
+        rule set_gain() -> Instruction
+            = "gain" _ channel:channel() _ db:$(['+' | '-']? ['0'..='9']+ ("." ['0'..='9']+)?) {?
+                db.parse().map(|db| Instruction::SetGain(channel, db)).or(Err("decibels"))
+            }
+
+        rule channel() -> dsl::Channel
+            = quiet!{ name:$(['a'..='z']+) {? dsl::Channel::from_name(name).ok_or("channel") } }
+            / expected!("melody, harmony or master")
+
+        rule ms() -> u16
+            = ms:uint() {? ms.try_into().or(Err("milliseconds")) }
+
+        rule sustain() -> f32
+            = sustain:ufloat() {? if sustain <= 1. { Ok(sustain) } else { Err("sustain between 0 and 1") } }
+
+        rule curve() -> dsl::Curve
+            = quiet!{ name:$(['a'..='z']+) {? dsl::Curve::from_name(name).ok_or("curve") } }
+            / expected!("curve")
+
+        rule wave_part() -> dsl::WavePart
+            = "melody" { dsl::WavePart::Melody } / "harmony" { dsl::WavePart::Harmony }
+
+        rule waveform() -> dsl::Waveform
+            = "pulse" width:(nbspace() width:ufloat() {?
+                if width > 0. && width < 1. { Ok(width) } else { Err("pulse width between 0 and 1") }
+            })? {
+                dsl::Waveform::Pulse(width.unwrap_or(dsl::Waveform::DEFAULT_PULSE_WIDTH))
+            }
+            / quiet!{ name:$(['a'..='z']+) {? dsl::Waveform::from_name(name).ok_or("waveform") } }
+            / expected!("waveform")
+
         rule set_key() -> Instruction
             = "key" _ key:key_name() { Instruction::SetKey(key) }
 
@@ -52,20 +224,31 @@ peg::parser! {
 
         rule note() -> dsl::Note
             = ties_to_prev:tie() num_half:note_mul_2()  pitch:pitch() num_twice:note_mul_2() is_dotted:dot() ties_to_next:tie() {
-                let mut numerator = if num_twice == 0 { 1 } else { num_twice * 2 };
-                let mut denominator = if num_half == 0 { 1 } else { num_half * 2 };
-                if is_dotted {
-                    numerator *= 3;
-                    denominator *= 2;
-                }
                 dsl::Note {
-                    duration: dsl::Duration::new(numerator, denominator),
+                    duration: duration(num_half, num_twice, is_dotted),
                     pitch,
                     ties_to_next,
                     ties_to_prev,
                 }
             }
 
+        rule play_rest() -> Instruction
+            = rest:rest() { Instruction::PlayRest(rest) }
+
+        // rests take the same ~ and . modifiers as notes, just with an r (or an
+        // R to stop the harmony too) where the scale degree would go
+        rule rest() -> dsl::Rest
+            = num_half:note_mul_2() stops_harmony:rest_kind() num_twice:note_mul_2() is_dotted:dot() {
+                dsl::Rest {
+                    duration: duration(num_half, num_twice, is_dotted),
+                    stops_harmony,
+                }
+            }
+
+        rule rest_kind() -> bool
+            = "r" { false }
+            / "R" { true }
+
         rule tie() -> bool
             = "_" { true }
             / "" { false }
@@ -79,14 +262,18 @@ peg::parser! {
             / "" { 0 }
 
         rule pitch() -> dsl::NotePitch
-            = octave:octave() num:uint() accidental:accidental() {
+            = octave:octave() num:scale_degree() accidental:accidental() {
                 dsl::NotePitch {
-                    enum_: dsl::NotePitchEnum::ScaleDegree(num as _),
+                    enum_: dsl::NotePitchEnum::ScaleDegree(num),
                     accidental,
                     octave,
                 }
             }
 
+        // degrees count from 1, there's no such thing as the 0th note of a scale
+        rule scale_degree() -> u8
+            = num:uint() {? if num == 0 { Err("scale degree starting at 1") } else { u8::try_from(num).or(Err("scale degree")) } }
+
         rule octave() -> i8
             = minuses:"-"+ { (minuses.len() as i8) * -1 }
             / pluses:"+"+ { (pluses.len() as i8) * 1 }
@@ -97,9 +284,8 @@ peg::parser! {
             / "b" { dsl::Accidental::Flat }
             / "" { dsl::Accidental::Natural }
 
-        // add support for accidentals later
-        rule key_name() -> dsl::Key
-            = abc:abc() { dsl::Key { abc, accidental: dsl::Accidental::Natural } }
+        pub rule key_name() -> dsl::Key
+            = abc:abc() accidental:accidental() { dsl::Key { abc, accidental } }
 
         rule abc() -> dsl::ABC
             = "A" { dsl::ABC::A }
@@ -111,21 +297,37 @@ peg::parser! {
             / "G" { dsl::ABC::G }
 
         rule set_scale() -> dsl::Instruction
-            = "scale" _ scale:scale_name() { dsl::Instruction::SetScale(scale) }
+            = "scale" _ scale:(custom_scale() / scale_name()) { dsl::Instruction::SetScale(scale) }
+
+        // the numbers have to be on the same line as the directive so the
+        // notes after it don't get mistaken for more of the scale
+        rule custom_scale() -> dsl::Scale
+            = "custom" nbspace() nums:(uint() ++ nbspace()) {?
+                let nums = nums.into_iter().map(u8::try_from).collect::<Result<Vec<_>, _>>();
+                nums.ok()
+                    .and_then(|nums| dsl::CustomScale::new(&nums))
+                    .map(dsl::Scale::Custom)
+                    .ok_or("steps between 1 and 12, or increasing notes starting from 0")
+            }
 
         rule scale_name() -> dsl::Scale
-            = "major" { dsl::Scale::Major }
-            / "minor" { dsl::Scale::Minor }
+            = quiet!{ name:$(['a'..='z' | '_']+) {? dsl::Scale::from_name(name).ok_or("scale name") } }
+            / expected!("scale name")
 
         rule set_harmony() -> dsl::Instruction
             = harmony:harmony() ":" { dsl::Instruction::SetHarmony(harmony) }
 
         rule harmony() -> dsl::Harmony
-            = downshift:downshift() chord_base:chord_base() add_7:add_7() {
+            = downshift:downshift() chord_base:chord_base() add_7:add_7() quality:chord_quality()? {
                 let shift = (downshift as i8 * -1);
-                dsl::Harmony {degree: chord_base.0, scale: chord_base.1, add_7, shift}
+                let scale = quality.unwrap_or(chord_base.1);
+                dsl::Harmony {degree: chord_base.0, scale, add_7, shift}
             }
 
+        // builds the chord out of another scale than the case implies, e.g. IV(lydian)
+        rule chord_quality() -> dsl::Scale
+            = "(" scale:scale_name() ")" { scale }
+
         rule downshift() -> usize
             = lts:"<"* { lts.len() }
 
@@ -149,12 +351,14 @@ peg::parser! {
             = "7" { true }
             / "" { false }
 
+        rule ufloat() -> f32
+            = n:$(['0'..='9']+ ("." ['0'..='9']+)?) {? n.parse().or(Err("not a number")) }
         rule uint() -> u128
             = int:$("0" / ['1' ..= '9']+ ['0' ..= '9']*) {? int.parse().or(Err("not a number")) }
         rule onespace() = [' ' | '\t']
         rule nbspace() = onespace()+
         rule newline() = "\n" / "\r\n"
-        rule whitespace() = (nbspace() / newline())+
+        rule whitespace() = (nbspace() / newline() / comment())+
         rule _() = quiet!{ whitespace() }
     }
 }
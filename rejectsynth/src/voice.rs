@@ -0,0 +1,459 @@
+use std::collections::VecDeque;
+
+use dsl::{
+    Channel, Duration, Envelope, Harmony, Instruction, Key, Note, NotePitch, Rest, Scale, WavePart,
+    Waveform,
+};
+
+use crate::check::Beats;
+use crate::mixer::db_to_gain;
+use crate::{
+    freqs_to_samples, key_semitones, ms_to_samples, scale_degree_to_semitones, semitones_to_freq,
+    Sound, Tone, DEFAULT_SAMPLE_RATE, GLIDE_MS,
+};
+
+/// One timeline of the song. Top level instructions make up the main voice,
+/// and every `voice name { ... }` block gets its own, so they all start at the
+/// beginning of the song and get mixed together as they're rendered.
+pub(crate) struct Voice {
+    pub(crate) name: Option<dsl::VoiceName>,
+
+    pub(crate) bpm: u16,
+    pub(crate) key: Key,
+    pub(crate) scale: Scale,
+    harmony: Option<Harmony>,
+    // whatever the chord played last, so any of its tones that are still in
+    // the next chord carry on where they left off
+    chord: Vec<Tone>,
+    // the chord keeps ringing underneath the melody until the harmony changes,
+    // this is how far into its envelope it's got
+    chord_sounding: bool,
+    chord_env_pos: usize,
+
+    // the melody is its own oscillator so a tied note can keep ringing while
+    // the chord underneath it changes
+    melody_phase: f32,
+    melody_volume: f32,
+    pub(crate) tied_from_prev: bool,
+    // how far into its envelope the melody is, for when it's tied
+    melody_env_pos: usize,
+
+    octave: i8,
+    volume: f32,
+    melody_gain: f32,
+    harmony_gain: f32,
+    melody_waveform: Waveform,
+    harmony_waveform: Waveform,
+    envelope: Envelope,
+
+    // indices into the song's instructions, in the order this voice plays them
+    pub(crate) instructions: Vec<usize>,
+    pub(crate) cursor: usize,
+    // rendered, but not mixed with the other voices yet. it starts at
+    // `pending_start` and can run on past `num_samples_rendered` when the last
+    // note is still releasing
+    pending: VecDeque<f32>,
+    pending_start: usize,
+    pub(crate) num_samples_rendered: usize,
+    // where the tempo last changed, and exactly how many beats it's been since.
+    // every note ends on the sample its beat lands on, rather than each one
+    // getting rounded and the rounding adding up
+    bpm_start_sample: usize,
+    beats_since_bpm: Beats,
+    pub(crate) sample_rate: u32,
+
+    pub(crate) on_harmony: Option<usize>,
+    pub(crate) off_on_next_tick: Option<usize>,
+}
+
+impl Voice {
+    pub(crate) fn new(name: Option<dsl::VoiceName>, bpm: u16, key: Key, scale: Scale) -> Self {
+        Self {
+            name,
+            bpm,
+            key,
+            scale,
+            harmony: None,
+            chord: vec![],
+            chord_sounding: false,
+            chord_env_pos: 0,
+            melody_phase: 0.,
+            melody_volume: 1.,
+            tied_from_prev: false,
+            melody_env_pos: 0,
+            octave: 0,
+            volume: 1.,
+            melody_gain: 1.,
+            harmony_gain: 1.,
+            melody_waveform: Waveform::Sine,
+            harmony_waveform: Waveform::Sine,
+            envelope: Envelope::default(),
+            instructions: vec![],
+            cursor: 0,
+            pending: VecDeque::new(),
+            pending_start: 0,
+            num_samples_rendered: 0,
+            bpm_start_sample: 0,
+            beats_since_bpm: Beats::ZERO,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            on_harmony: None,
+            off_on_next_tick: None,
+        }
+    }
+
+    pub(crate) fn next_instruction_index(&self) -> Option<usize> {
+        self.instructions.get(self.cursor).copied()
+    }
+
+    // moves on to the voice's next instruction, letting the chord go if that
+    // was the last one
+    pub(crate) fn advance(&mut self) {
+        self.cursor += 1;
+        if self.next_instruction_index().is_none() {
+            self.release_chord();
+        }
+    }
+
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub(crate) fn end_of_pending(&self) -> usize {
+        self.pending_start + self.pending.len()
+    }
+
+    // the next `n` samples of the voice, or however many there are if it's
+    // already finished
+    pub(crate) fn drain_pending(&mut self, n: usize) -> impl Iterator<Item = f32> + '_ {
+        self.pending_start += n;
+        self.pending.drain(..n.min(self.pending.len()))
+    }
+
+    pub(crate) fn set_gain(&mut self, channel: Channel, db: f32) {
+        match channel {
+            Channel::Melody => self.melody_gain = db_to_gain(db),
+            Channel::Harmony => self.harmony_gain = db_to_gain(db),
+            // the song context takes care of the whole mix
+            Channel::Master => {}
+        }
+    }
+
+    pub(crate) fn set_bpm(&mut self, bpm: u16) {
+        self.bpm = bpm;
+        self.bpm_start_sample = self.num_samples_rendered;
+        self.beats_since_bpm = Beats::ZERO;
+    }
+
+    pub(crate) fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    pub(crate) fn set_key(&mut self, key: Key) {
+        self.key = key;
+    }
+
+    // `silent` notes and rests only update the voice's state, they're the
+    // ones before the skip-to-note marker
+    pub(crate) fn eval(&mut self, instructions: &[Instruction], silent: bool) {
+        let inst = instructions[self.instructions[self.cursor]];
+        match inst {
+            Instruction::SetBPM(bpm) => self.set_bpm(bpm),
+            Instruction::SetKey(key) => self.key = key,
+            Instruction::SetScale(scale) => self.scale = scale,
+            Instruction::PlayNote(_) if silent => self.tied_from_prev = false,
+            Instruction::PlayNote(note) => self.render_note(note, instructions),
+            Instruction::PlayRest(rest) => {
+                self.tied_from_prev = false;
+                if rest.stops_harmony {
+                    self.release_chord();
+                    self.harmony = None;
+                }
+                if !silent {
+                    self.render_rest(rest);
+                }
+            }
+            Instruction::SetHarmony(harmony) => {
+                self.release_chord();
+                self.harmony = Some(harmony);
+            }
+            Instruction::SetOctave(octave) => self.octave = octave,
+            Instruction::SetVolume(volume) => self.volume = volume,
+            Instruction::SetWaveform(part, waveform) => {
+                if part != WavePart::Harmony {
+                    self.melody_waveform = waveform;
+                }
+                if part != WavePart::Melody {
+                    self.harmony_waveform = waveform;
+                }
+            }
+            Instruction::SetEnvelope(envelope) => self.envelope = envelope,
+            Instruction::SetGain(channel, db) => self.set_gain(channel, db),
+            // bars and beats only matter for checking the song's written down right
+            Instruction::SkipToNote
+            | Instruction::BeginVoice(_)
+            | Instruction::EndVoice
+            | Instruction::SetTimeSignature(_)
+            | Instruction::BarLine
+            | Instruction::BeatBoundary
+            | Instruction::SetStrict(_)
+            | Instruction::BeginTuplet(_)
+            | Instruction::EndTuplet => {}
+        }
+    }
+
+    // adds `samples` into the mix starting at the end of the voice so far, on
+    // top of anything that's still releasing from before
+    fn write(&mut self, samples: impl Iterator<Item = f32>) {
+        let offset = self.num_samples_rendered - self.pending_start;
+        for (i, sample) in samples.enumerate() {
+            let sample = sample * self.volume;
+            match self.pending.get_mut(offset + i) {
+                Some(pending) => *pending += sample,
+                None => self.pending.push_back(sample),
+            }
+        }
+    }
+
+    fn sound(&self, waveform: Waveform, volume: f32) -> Sound {
+        Sound {
+            waveform,
+            volume,
+            envelope: self.envelope,
+            sample_rate: self.sample_rate,
+        }
+    }
+
+    fn chord_freqs(&self) -> Vec<f32> {
+        self.chord_pitches()
+            .into_iter()
+            .map(semitones_to_freq)
+            .collect()
+    }
+
+    // in semitones from A4, the same as note_pitch
+    pub(crate) fn chord_pitches(&self) -> Vec<i32> {
+        let mut pitches = vec![];
+        if let Some(mut harmony) = self.harmony {
+            let base_of_chord =
+                self.tonic() + scale_degree_to_semitones(self.scale, harmony.degree);
+            pitches.push(base_of_chord);
+
+            // stacks every other note of the chord's scale on top of the base,
+            // which is thirds for the 7 note scales but not for pentatonics
+            let semitones_to_3 = scale_degree_to_semitones(harmony.scale, 3);
+            pitches.push(base_of_chord + semitones_to_3);
+
+            let semitones_to_5 = scale_degree_to_semitones(harmony.scale, 5);
+            pitches.push(base_of_chord + semitones_to_5);
+
+            if harmony.add_7 {
+                let semitones_to_7 = scale_degree_to_semitones(harmony.scale, 7);
+                pitches.push(base_of_chord + semitones_to_7);
+            }
+
+            while harmony.shift < 0 {
+                let last = pitches.pop().unwrap();
+                pitches.insert(0, last - 12);
+                harmony.shift += 1;
+            }
+        }
+
+        pitches
+    }
+
+    fn render_note(&mut self, n: Note, instructions: &[Instruction]) {
+        let freq = semitones_to_freq(self.note_pitch(n.pitch));
+        let chord_freqs = self.chord_freqs();
+        // the melody gets half of the volume when there's a chord under it, the
+        // same as the whole chord gets, and all of it when there isn't
+        let melody_share = if chord_freqs.is_empty() { 1. } else { 0.5 };
+        let melody_volume = melody_share * self.melody_gain;
+        let num_samples = self.take_samples(n.duration);
+
+        let continues_tie = std::mem::take(&mut self.tied_from_prev);
+        let ties_to_next = self.ties_into_next_note(n, instructions);
+        let env_start = if continues_tie {
+            self.melody_env_pos
+        } else {
+            0
+        };
+        let melody = Tone {
+            freq,
+            phase: self.melody_phase,
+        };
+        let (melody, ending_tones) = freqs_to_samples(
+            num_samples,
+            vec![melody],
+            self.sound(self.melody_waveform, melody_volume),
+            env_start,
+            !ties_to_next,
+        );
+        self.melody_phase = ending_tones[0].phase;
+        self.tied_from_prev = ties_to_next;
+        self.melody_env_pos = env_start + num_samples;
+
+        // when a tie crosses into a chord with a different number of notes the
+        // melody's share of the volume changes, so glide to it instead of jumping
+        let prev_volume = std::mem::replace(&mut self.melody_volume, melody_volume);
+        let num_samples_in_glide = ms_to_samples(GLIDE_MS, self.sample_rate);
+        let melody = melody.enumerate().map(move |(i, sample)| {
+            if !continues_tie || i >= num_samples_in_glide {
+                return sample;
+            }
+            let t = i as f32 / num_samples_in_glide as f32;
+            sample * (prev_volume / melody_volume * (1. - t) + t)
+        });
+
+        self.write(melody);
+        self.render_chord(num_samples, chord_freqs);
+        self.num_samples_rendered += num_samples;
+    }
+
+    fn render_rest(&mut self, rest: Rest) {
+        let num_samples = self.take_samples(rest.duration);
+        self.render_chord(num_samples, self.chord_freqs());
+        self.num_samples_rendered += num_samples;
+    }
+
+    // carries the chord on for another `num_samples`, unless the key or scale
+    // moved its notes since, then it gets let go and struck again
+    fn render_chord(&mut self, num_samples: usize, freqs: Vec<f32>) {
+        let same_pitch = |a: f32, b: f32| (a / b - 1.).abs() < 1e-4;
+        let moved = self.chord.len() != freqs.len()
+            || self
+                .chord
+                .iter()
+                .zip(&freqs)
+                .any(|(tone, &freq)| !same_pitch(tone.freq, freq));
+        if moved {
+            self.release_chord();
+        }
+        if !self.chord_sounding {
+            self.chord_env_pos = 0;
+        }
+        let tones = freqs
+            .iter()
+            .map(|&freq| {
+                // a tone that wasn't in the last chord starts from zero, which
+                // is at least where a sine wave would be anyway
+                let phase = self
+                    .chord
+                    .iter()
+                    .find(|tone| same_pitch(tone.freq, freq))
+                    .map_or(0., |tone| tone.phase);
+                Tone { freq, phase }
+            })
+            .collect();
+        let (samples, ending_tones) = freqs_to_samples(
+            num_samples,
+            tones,
+            self.sound(self.harmony_waveform, CHORD_SHARE * self.harmony_gain),
+            self.chord_env_pos,
+            false,
+        );
+        self.chord = ending_tones;
+        self.chord_sounding = !freqs.is_empty();
+        self.chord_env_pos += num_samples;
+        self.write(samples);
+    }
+
+    fn release_chord(&mut self) {
+        if !std::mem::take(&mut self.chord_sounding) {
+            return;
+        }
+        let (samples, _) = freqs_to_samples(
+            0,
+            self.chord.clone(),
+            self.sound(self.harmony_waveform, CHORD_SHARE * self.harmony_gain),
+            std::mem::take(&mut self.chord_env_pos),
+            true,
+        );
+        self.write(samples);
+    }
+
+    // exactly, the way the notes get timed, so it only gets rounded for
+    // showing
+    pub(crate) fn duration_ms(&self, duration: Duration) -> f32 {
+        let beats = duration.numerator as f32 / duration.denominator as f32;
+        beats * 60_000. / self.bpm as f32
+    }
+
+    // how many samples a note or rest that's `duration` long takes up, from
+    // wherever the last one ended
+    fn take_samples(&mut self, duration: Duration) -> usize {
+        self.beats_since_bpm += duration;
+        let samples_per_minute = self.sample_rate as u128 * 60;
+        let samples_since_bpm = self.beats_since_bpm.numerator() as u128 * samples_per_minute
+            / (self.beats_since_bpm.denominator() as u128 * self.bpm as u128);
+        self.bpm_start_sample + samples_since_bpm as usize - self.num_samples_rendered
+    }
+
+    // how loud the melody and the harmony are, before they're mixed
+    pub(crate) fn levels(&self) -> (f32, f32) {
+        (
+            self.volume * self.melody_gain,
+            self.volume * self.harmony_gain,
+        )
+    }
+
+    // a tie only joins two notes up if the next note in this voice ties back to
+    // this one and is the same pitch, otherwise this note gets released like
+    // any other
+    pub(crate) fn ties_into_next_note(&self, n: Note, instructions: &[Instruction]) -> bool {
+        if !n.ties_to_next {
+            return false;
+        }
+        for &i in &self.instructions[self.cursor + 1..] {
+            match instructions[i] {
+                Instruction::PlayNote(next) => return next.ties_to_prev && next.pitch == n.pitch,
+                Instruction::PlayRest(_)
+                | Instruction::SetKey(_)
+                | Instruction::SetScale(_)
+                | Instruction::SetOctave(_) => return false,
+                // the melody can't change shape halfway through a note
+                Instruction::SetWaveform(part, _) if part != WavePart::Harmony => return false,
+                Instruction::SetBPM(_)
+                | Instruction::SkipToNote
+                | Instruction::SetHarmony(_)
+                | Instruction::SetVolume(_)
+                | Instruction::SetWaveform(..)
+                | Instruction::SetEnvelope(_)
+                | Instruction::SetGain(..)
+                | Instruction::BeginVoice(_)
+                | Instruction::EndVoice
+                | Instruction::SetTimeSignature(_)
+                | Instruction::BarLine
+                | Instruction::BeatBoundary
+                | Instruction::SetStrict(_)
+                | Instruction::BeginTuplet(_)
+                | Instruction::EndTuplet => {}
+            }
+        }
+        false
+    }
+
+    /// How many semitones above (or below) A4 a note is, which is easy to turn
+    /// into a frequency or a MIDI note.
+    pub(crate) fn note_pitch(&self, pitch: NotePitch) -> i32 {
+        match pitch.enum_ {
+            dsl::NotePitchEnum::ScaleDegree(degree) => {
+                let offset = scale_degree_to_semitones(self.scale, degree);
+                let offset = match pitch.accidental {
+                    dsl::Accidental::Natural => offset,
+                    dsl::Accidental::Sharp => offset + 1,
+                    dsl::Accidental::Flat => offset - 1,
+                };
+                self.tonic() + offset + 12 * pitch.octave as i32
+            }
+        }
+    }
+
+    // the voice's octave moves its chords along with its notes
+    fn tonic(&self) -> i32 {
+        key_semitones(self.key) + 12 * self.octave as i32
+    }
+}
+
+// the chord's half of the volume, shared between however many notes it has
+// so a 7th chord isn't any louder than a triad
+const CHORD_SHARE: f32 = 0.5;
@@ -0,0 +1,78 @@
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavFormat {
+    Int16,
+    Float32,
+}
+
+impl WavFormat {
+    fn bits_per_sample(self) -> u16 {
+        match self {
+            WavFormat::Int16 => 16,
+            WavFormat::Float32 => 32,
+        }
+    }
+
+    // WAVE_FORMAT_PCM and WAVE_FORMAT_IEEE_FLOAT
+    fn format_tag(self) -> u16 {
+        match self {
+            WavFormat::Int16 => 1,
+            WavFormat::Float32 => 3,
+        }
+    }
+}
+
+/// Writes mono `samples` as a RIFF/WAVE file. Samples are expected to be
+/// between -1 and 1, anything outside of that gets clipped for 16-bit output.
+pub fn write_wav(
+    mut w: impl Write,
+    samples: &[f32],
+    sample_rate: u32,
+    format: WavFormat,
+) -> io::Result<()> {
+    const NUM_CHANNELS: u16 = 1;
+    let bytes_per_sample = format.bits_per_sample() / 8;
+    let block_align = NUM_CHANNELS * bytes_per_sample;
+    let byte_rate = sample_rate * block_align as u32;
+    let data_len = samples.len() as u32 * block_align as u32;
+
+    // non-PCM formats need the extended fmt chunk (with cbSize) and a fact chunk
+    let is_pcm = format == WavFormat::Int16;
+    let fmt_len: u32 = if is_pcm { 16 } else { 18 };
+    let fact_len: u32 = if is_pcm { 0 } else { 12 };
+    let riff_len = 4 + (8 + fmt_len) + fact_len + (8 + data_len);
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&riff_len.to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&fmt_len.to_le_bytes())?;
+    w.write_all(&format.format_tag().to_le_bytes())?;
+    w.write_all(&NUM_CHANNELS.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&byte_rate.to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&format.bits_per_sample().to_le_bytes())?;
+    if !is_pcm {
+        w.write_all(&0u16.to_le_bytes())?;
+        w.write_all(b"fact")?;
+        w.write_all(&4u32.to_le_bytes())?;
+        w.write_all(&(samples.len() as u32).to_le_bytes())?;
+    }
+
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    let mut w = io::BufWriter::new(w);
+    for &sample in samples {
+        match format {
+            WavFormat::Int16 => {
+                let sample = (sample.clamp(-1., 1.) * i16::MAX as f32).round() as i16;
+                w.write_all(&sample.to_le_bytes())?;
+            }
+            WavFormat::Float32 => w.write_all(&sample.to_le_bytes())?,
+        }
+    }
+    w.flush()
+}
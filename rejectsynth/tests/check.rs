@@ -0,0 +1,75 @@
+// every warning as the source it covers and its message
+fn warnings(src: &str) -> Vec<(&str, String)> {
+    let song = rejectsynth::parse(src).unwrap();
+    song.diagnostics
+        .iter()
+        .inspect(|diagnostic| assert!(diagnostic.is_warning))
+        .map(|diagnostic| (&src[diagnostic.l..diagnostic.r], diagnostic.to_string()))
+        .collect()
+}
+
+#[test]
+fn warns_about_short_bars() {
+    assert_eq!(
+        warnings("time 3/4 | 1 1 1 | 1 1 |"),
+        [(
+            "1 1 |",
+            "this bar is 2 beats long, but bars in 3/4 are 3 beats".to_string()
+        )]
+    );
+}
+
+#[test]
+fn warns_about_long_bars() {
+    assert_eq!(
+        warnings("1 1 1 1 | 1 1 1 1 1 |"),
+        [(
+            "1 1 1 1 1 |",
+            "this bar is 5 beats long, but bars in 4/4 are 4 beats".to_string()
+        )]
+    );
+}
+
+#[test]
+fn counts_tuplets_by_how_long_they_play() {
+    assert_eq!(warnings("3:( ~1 ~1 ~1 ) 1 1 1 |"), []);
+    assert_eq!(
+        warnings("3:( 1 1 1 ) 1 |"),
+        [(
+            "3:( 1 1 1 ) 1 |",
+            "this bar is 3 beats long, but bars in 4/4 are 4 beats".to_string()
+        )]
+    );
+}
+
+#[test]
+fn leaves_bar_lines_with_nothing_before_them_alone() {
+    assert_eq!(warnings("| 1 1 1 1 | | 1 1 1 1 |"), []);
+}
+
+#[test]
+fn says_beat_for_a_beat_or_less() {
+    assert_eq!(
+        warnings("strict ~1 , 1 |"),
+        [
+            (
+                "~1 ,",
+                "this group is 1/2 beat long, but in strict mode the groups between commas are 1 beat"
+                    .to_string()
+            ),
+            (
+                "~1 , 1 |",
+                "this bar is 1 1/2 beats long, but bars in 4/4 are 4 beats".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn plays_duplets_and_quadruplets_in_the_time_of_three() {
+    assert_eq!(warnings("time 6/8 | 2:( ~1 ~1 ) ~1 ~1 ~1 |"), []);
+    assert_eq!(warnings("time 6/8 | 2:3( ~1 ~1 ) ~1 ~1 ~1 |"), []);
+    assert_eq!(warnings("time 6/8 | 4:( ~1 ~1 ~1 ~1 ) ~1 ~1 ~1 |"), []);
+    // or whatever they're asked to
+    assert_eq!(warnings("4:2( 1 1 1 1 ) 1 1 |"), []);
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use common::render;
+
+// the furthest a full volume sine can move between two samples at this
+// frequency, anything more than that at a note boundary is a click
+fn max_step(freq: f32) -> f32 {
+    2. * std::f32::consts::PI * freq / rejectsynth::DEFAULT_SAMPLE_RATE as f32
+}
+
+fn biggest_step(samples: &[f32]) -> f32 {
+    samples
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).abs())
+        .fold(0., f32::max)
+}
+
+#[test]
+fn held_chord_carries_its_phase_across_notes() {
+    // no attack or release, so nothing hides a jump in any of the chord tones
+    let samples = render("bpm 200 key A env 0 0 1 0 I: 1 1 1 1 1 1 1 1");
+    // the highest tone of the chord is the fifth, E5
+    assert!(biggest_step(&samples) <= max_step(659.3));
+}
+
+#[test]
+fn melody_carries_its_phase_from_pitch_to_pitch() {
+    let samples = render("bpm 200 key A env 0 0 1 0 I: 1 3 5 8 5 3 1");
+    // up to A5 in the melody this time
+    assert!(biggest_step(&samples) <= max_step(880.));
+}
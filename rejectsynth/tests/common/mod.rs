@@ -0,0 +1,6 @@
+use rejectsynth::SongContext;
+
+pub fn render(song: &str) -> Vec<f32> {
+    let song = rejectsynth::parse(song).unwrap();
+    SongContext::default(song.instructions()).render_to_end()
+}
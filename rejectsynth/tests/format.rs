@@ -0,0 +1,63 @@
+use rejectsynth::{format_song, parse};
+
+// Instruction doesn't have PartialEq, but its Debug output has everything in it
+fn instructions(src: &str) -> String {
+    format!("{:?}", parse(src).unwrap().instructions())
+}
+
+// formatting doesn't change what the song plays or lose any comments, and
+// formatting it again doesn't change anything
+fn check(src: &str) -> String {
+    let formatted = format_song(src).unwrap();
+    assert_eq!(instructions(&formatted), instructions(src), "{formatted}");
+    for comment in parse(src).unwrap().comments {
+        let comment = src[comment].trim_end();
+        assert!(formatted.contains(comment), "{comment:?} in {formatted}");
+    }
+    assert_eq!(format_song(&formatted).unwrap(), formatted);
+    formatted
+}
+
+#[test]
+fn formats_kalm() {
+    check(include_str!("../../songs/kalm.rej"));
+}
+
+#[test]
+fn formats_comments() {
+    check("bpm 90 // not too fast\n/* a comment\n   over two lines */ key D\n1   2 3 // the end\n\n\n\n4");
+}
+
+#[test]
+fn formats_voices() {
+    check("voice bass {\noctave -1\n1 1 5 5\n}\nvoice lead {   wave saw 3 4 }\nI: 1 2   ~3 ~4");
+}
+
+#[test]
+fn keeps_comments_in_voice_headers() {
+    assert_eq!(
+        check("voice bass /* low */ {\n1 1\n}"),
+        "voice bass /* low */ {\n    1 1\n}\n"
+    );
+    assert_eq!(
+        check("voice bass // the bass\n{ 1 1 }"),
+        "voice bass // the bass\n{ 1 1 }\n"
+    );
+}
+
+#[test]
+fn formats_tuplets() {
+    check("3:( 1 2 3 ) 5:4(~1 ~2 ~3 ~4 ~5)\nIV: 3:(  ~1 ~2 ~3 ) 4");
+}
+
+#[test]
+fn formats_bar_lines_and_commas() {
+    check("time 3/4 strict\n| 1 2 3 |  ~1 ~2, ~3 ~4 ,5 6 |\nV7: ~1 ~2 ~3 ~4 ,~5 ~6  | 1. ~2 |");
+}
+
+#[test]
+fn formats_directives() {
+    check(
+        "bpm 100 key F# scale minor time 6/8 strict 3/8\nvolume 0.5 wave harmony square\nenv 10 20 0.5 30 exp\ngain melody -3  octave +1\n1 2 3 , 4 5 6",
+    );
+}
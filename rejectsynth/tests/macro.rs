@@ -0,0 +1,23 @@
+use dsl::Instruction;
+use r#macro::m;
+
+// the macro builds the same instructions as the text does. Instruction doesn't
+// have PartialEq, but its Debug output has everything in it
+fn assert_same(from_macro: Vec<Instruction>, text: &str) {
+    let from_text = rejectsynth::parse(text).unwrap().instructions();
+    assert_eq!(format!("{from_macro:?}"), format!("{from_text:?}"));
+}
+
+#[test]
+fn keys_with_sharps_and_flats() {
+    // `F#` isn't a Rust token, so the macro needs the space
+    assert_same(m! { key F # 1 }, "key F# 1");
+    assert_same(m! { key C # scale minor 1 }, "key C# scale minor 1");
+    assert_same(m! { key Bb 1 }, "key Bb 1");
+    assert_same(m! { key Eb scale dorian 1 }, "key Eb scale dorian 1");
+}
+
+#[test]
+fn notes_with_sharps_and_flats() {
+    assert_same(m! { 4# 3b -1# ~7b 2 }, "4# 3b -1# ~7b 2");
+}
@@ -0,0 +1,214 @@
+// a tied note, and a chord that changes halfway through
+const SONG: &str = "bpm 120 key G\nI: 1 2_ _2 IV: 3";
+
+fn export(song: &str) -> Vec<u8> {
+    let song = rejectsynth::parse(song).unwrap();
+    let mut bytes = vec![];
+    rejectsynth::write_midi(&mut bytes, &song.instructions()).unwrap();
+    bytes
+}
+
+fn vlq(bytes: &[u8], i: &mut usize) -> u32 {
+    let mut n = 0;
+    loop {
+        let byte = bytes[*i];
+        *i += 1;
+        n = (n << 7) | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+    }
+}
+
+// every track's events with the tick they happen on. write_midi doesn't use
+// running status, so every event has its own status byte
+fn tracks(bytes: &[u8]) -> Vec<Vec<(u32, Vec<u8>)>> {
+    let mut tracks = vec![];
+    let mut i = 14;
+    while i < bytes.len() {
+        assert_eq!(&bytes[i..i + 4], b"MTrk");
+        let len = u32::from_be_bytes(bytes[i + 4..i + 8].try_into().unwrap()) as usize;
+        let (mut j, end) = (i + 8, i + 8 + len);
+        let (mut now, mut events) = (0, vec![]);
+        while j < end {
+            now += vlq(bytes, &mut j);
+            let start = j;
+            match bytes[j] {
+                0xff => {
+                    j += 2;
+                    let len = vlq(bytes, &mut j) as usize;
+                    j += len;
+                }
+                _ => j += 3,
+            }
+            events.push((now, bytes[start..j].to_vec()));
+        }
+        tracks.push(events);
+        i = end;
+    }
+    tracks
+}
+
+fn track_name(name: &str) -> Vec<u8> {
+    [&[0xff, 0x03, name.len() as u8], name.as_bytes()].concat()
+}
+
+#[test]
+fn exports_the_header_tempo_key_and_notes() {
+    let bytes = export(SONG);
+    assert_eq!(&bytes[..4], b"MThd");
+    // 6 bytes of header: format 1, a conductor track plus a melody and a
+    // harmony track, 480 ticks a quarter note
+    assert_eq!(&bytes[4..14], &[0, 0, 0, 6, 0, 1, 0, 3, 0x01, 0xe0]);
+
+    let tracks = tracks(&bytes);
+    assert_eq!(tracks.len(), 3);
+    let end = |tick| (tick, vec![0xff, 0x2f, 0]);
+
+    // 500000 microseconds a quarter note is 120 bpm, and G major has a sharp
+    assert_eq!(
+        tracks[0],
+        [
+            (0, track_name("rejectsynth")),
+            (0, vec![0xff, 0x51, 3, 0x07, 0xa1, 0x20]),
+            (0, vec![0xff, 0x59, 2, 1, 0]),
+            end(0),
+        ]
+    );
+
+    // G5 (key G starts from the G above A4), then A5 tied over two beats as
+    // one note, then B5
+    assert_eq!(
+        tracks[1],
+        [
+            (0, track_name("melody")),
+            (0, vec![0x90, 79, 100]),
+            (480, vec![0x80, 79, 0x40]),
+            (480, vec![0x90, 81, 100]),
+            (1440, vec![0x80, 81, 0x40]),
+            (1440, vec![0x90, 83, 100]),
+            (1920, vec![0x80, 83, 0x40]),
+            end(1920),
+        ]
+    );
+
+    // G B D held through the tie, then let go and C E G struck for IV
+    assert_eq!(
+        tracks[2],
+        [
+            (0, track_name("harmony")),
+            (0, vec![0x91, 79, 100]),
+            (0, vec![0x91, 83, 100]),
+            (0, vec![0x91, 86, 100]),
+            (1440, vec![0x81, 79, 0x40]),
+            (1440, vec![0x81, 83, 0x40]),
+            (1440, vec![0x81, 86, 0x40]),
+            (1440, vec![0x91, 84, 100]),
+            (1440, vec![0x91, 88, 100]),
+            (1440, vec![0x91, 91, 100]),
+            (1920, vec![0x81, 84, 0x40]),
+            (1920, vec![0x81, 88, 0x40]),
+            (1920, vec![0x81, 91, 0x40]),
+            end(1920),
+        ]
+    );
+}
+
+#[test]
+fn keeps_long_songs_on_the_beat() {
+    // a 16th note at 70 bpm isn't a whole number of microseconds, and a
+    // thousand beats of them shouldn't drift off the beat, in either voice
+    let notes = "~~1 ".repeat(4000);
+    let bytes = export(&format!("bpm 70\n{notes}\nvoice a {{ {notes} }}"));
+    let both = tracks(&bytes);
+    for melody in [&both[1], &both[2]] {
+        let ends = melody.iter().filter(|(_, event)| event[0] == 0x80);
+        for (i, (tick, _)) in ends.enumerate() {
+            assert_eq!(*tick, (i as u32 + 1) * 120);
+        }
+    }
+
+    // and beats are still a quarter note's worth of ticks after the tempo
+    // changes
+    let bytes = export("bpm 70 ~~1 ~~1 ~~1 ~~1 bpm 133 ~~1 ~~1 ~~1 ~~1");
+    let melody = &tracks(&bytes)[1];
+    let ends = melody.iter().filter(|(_, event)| event[0] == 0x80);
+    let ticks = ends.map(|(tick, _)| *tick).collect::<Vec<_>>();
+    assert_eq!(ticks, [120, 240, 360, 480, 600, 720, 840, 960]);
+}
+
+// a format 0 file with one track, 480 ticks a quarter note
+fn smf(track: &[u8]) -> Vec<u8> {
+    let mut bytes = b"MThd".to_vec();
+    bytes.extend([0, 0, 0, 6, 0, 0, 0, 1, 0x01, 0xe0]);
+    bytes.extend(b"MTrk");
+    bytes.extend((track.len() as u32).to_be_bytes());
+    bytes.extend(track);
+    bytes.extend([0x00, 0xff, 0x2f, 0x00]);
+    bytes
+}
+
+fn import(bytes: &[u8]) -> std::io::Result<String> {
+    rejectsynth::import_midi(bytes, None, None)
+}
+
+#[test]
+fn imports_what_it_exports() {
+    let kalm = include_str!("../../songs/kalm.rej");
+    let song = import(&export(kalm)).unwrap();
+    // the melody comes back note for note, even if it's spelled differently
+    assert_eq!(tracks(&export(&song))[1], tracks(&export(kalm))[1]);
+
+    assert_eq!(
+        import(&export(SONG)).unwrap(),
+        "bpm 120 key G scale major\n1 2~ 3\n"
+    );
+}
+
+#[test]
+fn truncated_files_are_errors() {
+    let bytes = export(SONG);
+    // in the middle of the header, and in the middle of the last track
+    assert!(import(&bytes[..10]).is_err());
+    assert!(import(&bytes[..bytes.len() - 5]).is_err());
+}
+
+// C for a beat, then D for a beat
+const C_THEN_D: &str = "bpm 120 key C scale major\noctave -1\n1 2\n";
+
+#[test]
+fn takes_note_on_with_no_velocity_as_note_off() {
+    let bytes = smf(&[
+        0x00, 0x90, 0x3c, 0x64, //
+        0x83, 0x60, 0x90, 0x3c, 0x00, //
+        0x00, 0x90, 0x3e, 0x64, //
+        0x83, 0x60, 0x90, 0x3e, 0x00,
+    ]);
+    assert_eq!(import(&bytes).unwrap(), C_THEN_D);
+}
+
+#[test]
+fn imports_running_status() {
+    // everything after the first note on leaves its status out
+    let bytes = smf(&[
+        0x00, 0x90, 0x3c, 0x64, //
+        0x83, 0x60, 0x3c, 0x00, //
+        0x00, 0x3e, 0x64, //
+        0x83, 0x60, 0x3e, 0x00,
+    ]);
+    assert_eq!(import(&bytes).unwrap(), C_THEN_D);
+
+    // but not over a meta event
+    let bytes = smf(&[
+        0x00, 0x90, 0x3c, 0x64, //
+        0x00, 0xff, 0x01, 0x02, b'h', b'i', //
+        0x83, 0x60, 0x3c, 0x00,
+    ]);
+    assert!(import(&bytes).is_err());
+}
+
+#[test]
+fn files_without_notes_are_errors() {
+    assert!(import(&smf(&[0x00, 0xff, 0x51, 3, 0x07, 0xa1, 0x20])).is_err());
+    assert!(import(&smf(&[])).is_err());
+}
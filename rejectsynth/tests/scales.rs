@@ -0,0 +1,118 @@
+mod common;
+
+use common::render;
+
+// how many semitones above A4 the note at `offset` plays, so C5 (the tonic in
+// C, which songs are in unless they say otherwise) is 3
+fn semitones_at(song: &str, offset: usize) -> f32 {
+    let note = rejectsynth::describe_at(song, offset).unwrap();
+    12. * (note.freq().unwrap() / 440.).log2()
+}
+
+fn assert_semitones(song: &str, offset: usize, expected: i32) {
+    let semitones = semitones_at(song, offset);
+    assert!(
+        (semitones - expected as f32).abs() < 0.01,
+        "{semitones} semitones at {offset} in {song:?}, not {expected}"
+    );
+}
+
+#[test]
+fn long_custom_scales_go_up_past_an_i8_of_semitones() {
+    // the steps add up to 132 semitones, 11 octaves of them
+    let song = "scale custom 11 11 11 11 11 11 11 11 11 11 11 11\n1 12 13";
+    assert!(!render(song).is_empty());
+    let notes = song.find('\n').unwrap() + 1;
+    assert_semitones(song, notes, 3);
+    assert_semitones(song, notes + 2, 3 + 11 * 11);
+    assert_semitones(song, notes + 5, 3 + 132);
+}
+
+#[test]
+fn high_degrees_of_wide_custom_scales_go_up_past_an_i8_of_semitones() {
+    // the 13th degree of a scale that goes up an octave a step is 4 of its
+    // "octaves" of 36 semitones up, 144 semitones
+    let song = "scale custom 12 12 12\n13 III: 1";
+    assert!(!render(song).is_empty());
+    assert_semitones(song, song.find("13").unwrap(), 3 + 144);
+
+    // III's chord starts on the 3rd degree, two octaves up
+    let note = rejectsynth::describe_at(song, song.len() - 1).unwrap();
+    assert_eq!(note.chord(), ["C7", "E7", "G7"]);
+}
+
+// every note on the song's last line, in semitones above A4
+fn line_semitones(song: &str) -> Vec<i32> {
+    let line = song.rfind('\n').map_or(0, |i| i + 1);
+    let words = song[line..].split(' ').scan(line, |offset, word| {
+        let at = *offset;
+        *offset += word.len() + 1;
+        Some(at)
+    });
+    words
+        .map(|offset| semitones_at(song, offset).round() as i32)
+        .collect()
+}
+
+// the chord under the note at the end of the song, in semitones above A4,
+// from names like C5 or C#5/Db5
+fn chord_semitones(song: &str) -> Vec<i32> {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    let note = rejectsynth::describe_at(song, song.len() - 1).unwrap();
+    let chord = note.chord().into_iter().map(|name| {
+        let name = name.split('/').next().unwrap();
+        let (pitch, octave) = name.split_at(name.len() - 1);
+        let pitch = NAMES.iter().position(|&p| p == pitch).unwrap() as i32;
+        pitch - 9 + 12 * (octave.parse::<i32>().unwrap() - 4)
+    });
+    chord.collect()
+}
+
+#[test]
+fn modes_have_their_own_steps() {
+    for (scale, steps) in [
+        ("major", [0, 2, 4, 5, 7, 9, 11, 12]),
+        ("minor", [0, 2, 3, 5, 7, 8, 10, 12]),
+        ("dorian", [0, 2, 3, 5, 7, 9, 10, 12]),
+        ("phrygian", [0, 1, 3, 5, 7, 8, 10, 12]),
+        ("lydian", [0, 2, 4, 6, 7, 9, 11, 12]),
+        ("mixolydian", [0, 2, 4, 5, 7, 9, 10, 12]),
+        ("locrian", [0, 1, 3, 5, 6, 8, 10, 12]),
+        ("harmonic_minor", [0, 2, 3, 5, 7, 8, 11, 12]),
+        ("melodic_minor", [0, 2, 3, 5, 7, 9, 11, 12]),
+    ] {
+        let song = format!("scale {scale}\n1 2 3 4 5 6 7 8");
+        let expected = steps.map(|step| 3 + step);
+        assert_eq!(line_semitones(&song), expected, "{scale}");
+    }
+}
+
+#[test]
+fn degrees_past_the_end_of_short_scales_go_round_again() {
+    // the 6th degree of a 5 note scale is the 1st an octave up
+    let song = "scale major_pentatonic\n1 2 3 4 5 6 7 11";
+    assert_eq!(line_semitones(song), [3, 5, 7, 10, 12, 15, 17, 27]);
+    let song = "scale blues\n1 2 3 4 5 6 7";
+    assert_eq!(line_semitones(song), [3, 6, 8, 9, 10, 13, 15]);
+}
+
+#[test]
+fn octaves_shift_degrees_of_short_scales() {
+    let song = "scale minor_pentatonic\n+2 -4 ++6";
+    assert_eq!(line_semitones(song), [3 + 3 + 12, 3 + 7 - 12, 3 + 12 + 24]);
+}
+
+#[test]
+fn builds_chords_out_of_pentatonic_scales() {
+    // every other note of the scale, so C E A rather than C E G
+    assert_eq!(chord_semitones("I(major_pentatonic): 1"), [3, 7, 12]);
+    // A D G, on the 6th degree of C major
+    assert_eq!(chord_semitones("vi(minor_pentatonic): 1"), [12, 17, 22]);
+    // on the 4th degree of the pentatonic scale, which is G
+    assert_eq!(
+        chord_semitones("scale major_pentatonic\nIV: 1"),
+        [10, 14, 17]
+    );
+}
@@ -0,0 +1,39 @@
+// every token as the text it covers and its type
+fn tokens(song: &str) -> Vec<(&str, String)> {
+    let lines = song.split('\n').collect::<Vec<_>>();
+    let syntaxes = rejectsynth::syntax(song).unwrap();
+    syntaxes
+        .iter()
+        .map(|syntax| {
+            let line = lines[syntax.line_no];
+            let text = &line[syntax.col_no..syntax.col_no + syntax.len];
+            (text, syntax.node_type())
+        })
+        .collect()
+}
+
+#[test]
+fn comments_in_instructions_are_part_of_them() {
+    assert_eq!(
+        tokens("bpm /* fast */ 180 // the end"),
+        [
+            ("bpm /* fast */ 180", "SetBPM".to_string()),
+            ("// the end", "Comment".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn tokens_stop_at_line_breaks() {
+    assert_eq!(
+        tokens("voice a // the bass\n  { 1 }\n/* two\n   lines */"),
+        [
+            ("voice a // the bass", "BeginVoice".to_string()),
+            ("{", "BeginVoice".to_string()),
+            ("1", "PlayNote".to_string()),
+            ("}", "EndVoice".to_string()),
+            ("/* two", "Comment".to_string()),
+            ("lines */", "Comment".to_string()),
+        ]
+    );
+}
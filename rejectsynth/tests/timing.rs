@@ -0,0 +1,38 @@
+mod common;
+
+use common::render;
+use rejectsynth::SongContext;
+
+// 70 beats at 70 bpm is exactly a minute
+const ONE_MINUTE: usize = rejectsynth::DEFAULT_SAMPLE_RATE as usize * 60;
+
+#[test]
+fn beats_add_up_to_exactly_the_song_length() {
+    // no release, so the song ends right when its last note does
+    let song = format!("bpm 70 env 0 0 1 0 {}", "1 ".repeat(70));
+    assert_eq!(render(&song).len(), ONE_MINUTE);
+}
+
+#[test]
+fn triplets_add_up_to_exactly_the_song_length() {
+    let song = format!("bpm 70 env 0 0 1 0 {}", "3:( ~1 ~2 ~3 ) ".repeat(70));
+    assert_eq!(render(&song).len(), ONE_MINUTE);
+}
+
+#[test]
+fn other_sample_rates_are_just_as_exact() {
+    let song = format!("bpm 70 env 0 0 1 0 {}", "3:( ~1 ~2 ~3 ) ".repeat(70));
+    let song = rejectsynth::parse(&song).unwrap();
+    let mut ctx = SongContext::default(song.instructions());
+    ctx.set_sample_rate(48_000);
+    assert_eq!(ctx.render_to_end().len(), 48_000 * 60);
+}
+
+#[test]
+fn describes_notes_as_long_as_they_play() {
+    // a 16th note at 70 bpm is 214.28... ms, not 857 / 4
+    let note = rejectsynth::describe_at("bpm 70 ~~1", 7).unwrap();
+    assert!((note.duration_ms - 60_000. / 70. / 4.).abs() < 0.001);
+    let note = rejectsynth::describe_at("bpm 70 3:( ~1 ~2 ~3 )", 11).unwrap();
+    assert!((note.duration_ms - 60_000. / 70. / 3.).abs() < 0.001);
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use common::render;
+
+// quiet enough that the limiter leaves them alone, even where a note's release
+// overlaps the next one
+const SHORT: &str = "voice a { bpm 120 volume 0.25 env 0 0 1 50 1 1 }";
+const LONG: &str = "voice b { bpm 120 volume 0.25 env 0 0 1 50 3 3 3 3 }";
+
+#[test]
+fn voices_play_at_the_same_time() {
+    let short = render(SHORT);
+    let long = render(LONG);
+    let both = render(&format!("{SHORT}\n{LONG}"));
+
+    // 4 beats at 120 bpm, then the 50 ms release, and the short voice doesn't
+    // make it any longer or hold the long one up
+    let release = rejectsynth::DEFAULT_SAMPLE_RATE as usize / 20;
+    assert_eq!(
+        long.len(),
+        2 * rejectsynth::DEFAULT_SAMPLE_RATE as usize + release
+    );
+    assert_eq!(both.len(), long.len());
+
+    // each voice gets half of the mix when there are two of them, and where
+    // they overlap they're added together
+    assert!(short.len() < long.len());
+    for (i, &sample) in both.iter().enumerate() {
+        let expected = (short.get(i).copied().unwrap_or(0.) + long[i]) / 2.;
+        assert!(
+            (sample - expected).abs() < 1e-6,
+            "{sample} at {i}, not {expected}"
+        );
+    }
+    // and they really do overlap
+    assert!(short
+        .iter()
+        .zip(&long)
+        .any(|(a, b)| a.abs() > 0.05 && b.abs() > 0.05));
+}
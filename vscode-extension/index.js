@@ -9,6 +9,7 @@ const TOKEN_TYPES = [
   'number',
   'operator',
   'parameter',
+  'comment',
 ]
 
 const TOKEN_TYPE_INIDICES = {};
@@ -16,26 +17,90 @@ for (let i = 0; i < TOKEN_TYPES.length; i++) {
   TOKEN_TYPE_INIDICES[TOKEN_TYPES[i]] = i;
 }
 
+// rejectsynth.sampleRate in the settings, songs get rendered at whatever the
+// speaker's playing at
+function sampleRate() {
+  return vscode.workspace.getConfiguration('rejectsynth').get('sampleRate', 44100);
+}
+
+const diagnostics = vscode.languages.createDiagnosticCollection('rejectsynth');
+
+// reject.syntax throws a RejectError when the song doesn't parse, and
+// reject.diagnostics returns them as warnings when it does
+// rejectsynth's offsets are in UTF-8 bytes, vscode's in UTF-16 code units
+function positionAtByte(doc, bytes, byte) {
+  return doc.positionAt(bytes.subarray(0, byte).toString().length);
+}
+
+function toDiagnostic(doc, err, bytes = Buffer.from(doc.getText())) {
+  if (!(err instanceof reject.RejectError)) throw err;
+  // warnings about bars can go over several lines
+  const start = positionAtByte(doc, bytes, err.l);
+  const end = positionAtByte(doc, bytes, Math.max(err.r, err.l + 1));
+  const severity = err.is_warning ? vscode.DiagnosticSeverity.Warning : vscode.DiagnosticSeverity.Error;
+  return new vscode.Diagnostic(new vscode.Range(start, end), err.full_message, severity);
+}
+
+function parseSyntaxes(doc) {
+  try {
+    const text = doc.getText();
+    const syntaxes = reject.syntax(text);
+    const bytes = Buffer.from(text);
+    diagnostics.set(doc.uri, reject.diagnostics(text).map((err) => toDiagnostic(doc, err, bytes)));
+    return syntaxes;
+  } catch (err) {
+    diagnostics.set(doc.uri, [toDiagnostic(doc, err)]);
+    return [];
+  }
+}
+
+function songIterator(doc, l, r) {
+  try {
+    return reject.WasmSongIterator.from_song_text(doc.getText(), l, r, sampleRate());
+  } catch (err) {
+    diagnostics.set(doc.uri, [toDiagnostic(doc, err)]);
+    vscode.window.showErrorMessage(`rejectsynth: ${err.message}`);
+    return undefined;
+  }
+}
+
 class MySemanticTokensProvider {
   async provideDocumentSemanticTokens(doc) {
     const builder = new vscode.SemanticTokensBuilder();
-    for (const syntax of reject.syntax(doc.getText())) {
+    for (const syntax of parseSyntaxes(doc)) {
       let token_type = "keyword";
       switch (syntax.node_type) {
         case "SetKey":
         case "SetBPM":
         case "SetScale":
+        case "SetOctave":
+        case "SetVolume":
+        case "SetWaveform":
+        case "SetEnvelope":
+        case "SetGain":
+        case "BeginVoice":
+        case "SetTimeSignature":
+        case "SetStrict":
           token_type = "keyword";
           break;
         case "PlayNote":
+        case "PlayRest":
           token_type = "number";
           break;
         case "SkipToNote":
+        case "EndVoice":
+        case "BarLine":
+        case "BeatBoundary":
+        case "BeginTuplet":
+        case "EndTuplet":
           token_type = "operator";
           break;
         case "SetHarmony":
           token_type = "parameter";
           break;
+        case "Comment":
+          token_type = "comment";
+          break;
         default:
           console.error(new Error("Unknown node type: " + syntax.node_type));
       }
@@ -53,7 +118,7 @@ function resetSpeaker() {
   speaker = new Speaker({
     channels: 1,
     bitDepth: 32,
-    sampleRate: 44100,
+    sampleRate: sampleRate(),
     float: true,
   });
 }
@@ -63,7 +128,7 @@ resetSpeaker();
 let playbackBGDecorationType;
 
 function audioLengthMs(numSamples) {
-  return numSamples / 44100 * 1000;
+  return numSamples / sampleRate() * 1000;
 }
 
 function clearDecorations() {
@@ -107,6 +172,8 @@ function setLastPlayMs(source, offset=0) {
 }
 
 function activate(context) {
+  context.subscriptions.push(diagnostics);
+
   context.subscriptions.push(vscode.window.onDidChangeTextEditorSelection(e => {
     vscode.commands.executeCommand('rejectsynth.playSelection');
   }));
@@ -123,7 +190,7 @@ function activate(context) {
     let l = e.document.offsetAt(change.range.start);
     let r = e.document.offsetAt(change.range.end);
     if (l !== r) throw new Error("expected range to just be a single character, update our assumptions");
-    let samples = reject.playback_for_note_input(e.document.getText(), l);
+    let samples = reject.playback_for_note_input(e.document.getText(), l, sampleRate());
     if (samples.length > 0) {
       lastPlayMs = Date.now();
 
@@ -155,6 +222,26 @@ function activate(context) {
     )
   );
 
+  context.subscriptions.push(vscode.languages.registerHoverProvider(
+    { language: 'rejectsynth' },
+    {
+      provideHover: (doc, position) => {
+        // describe_at works in UTF-8 bytes, vscode in UTF-16 code units
+        const text = doc.getText();
+        const bytes = Buffer.from(text);
+        const offset = Buffer.byteLength(text.slice(0, doc.offsetAt(position)), 'utf8');
+        const note = reject.describe_at(text, offset);
+        if (!note) return;
+        const lines = [note.name ? `${note.name}, ${note.freq.toFixed(2)} Hz` : 'rest'];
+        const beats = Math.round(note.beats * 1000) / 1000;
+        lines.push(`${beats} beat${beats === 1 ? '' : 's'}, ${Math.round(note.duration_ms)} ms`);
+        if (note.chord.length > 0) lines.push(`over ${note.chord.join(' ')}`);
+        const range = new vscode.Range(positionAtByte(doc, bytes, note.l), positionAtByte(doc, bytes, note.r));
+        return new vscode.Hover(lines.join('\n\n'), range);
+      }
+    },
+  ));
+
   context.subscriptions.push(vscode.languages.registerDocumentSemanticTokensProvider(
     { language: 'rejectsynth' },
 
@@ -174,7 +261,8 @@ function activate(context) {
       const editor = vscode.window.activeTextEditor;
       if (!editor) return;
 
-      const iter = reject.WasmSongIterator.from_song_text(editor.document.getText());
+      const iter = songIterator(editor.document);
+      if (!iter) return;
       const iterStreamer = new IterStreamer(iter);
 
 
@@ -203,7 +291,8 @@ function activate(context) {
       const l = editor.document.offsetAt(editor.selection.start);
       const r = editor.document.offsetAt(editor.selection.end);
 
-      const iter = reject.WasmSongIterator.from_song_text(editor.document.getText(), l, r);
+      const iter = songIterator(editor.document, l, r);
+      if (!iter) return;
       const iterStreamer = new IterStreamer(iter);
 
       let disposableStatusBarItem = vscode.window.createStatusBarItem(vscode.StatusBarAlignment.Right, 100);